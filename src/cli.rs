@@ -0,0 +1,420 @@
+//! The command line: parses the arguments and runs the mode they select.
+
+mod batch;
+mod report;
+mod service;
+
+use payment_engine::{
+    AppError, AsOf, Checkpoints, CreditLimits, DataFormat, EngineConfig, ExpectedAccounts, FeeSchedule,
+    HistoryError, OutputOrder, OutputRecord, OverLimitPolicy, PaymentEngine, RedisputePolicy, Snapshot,
+    DEFAULT_CHECKPOINT_INTERVAL,
+};
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+/// The address `serve` listens on unless `--listen` is given.
+const DEFAULT_HTTP_LISTEN: &str = "127.0.0.1:8080";
+
+/// The address `listen` listens on unless `--listen` is given.
+const DEFAULT_TCP_LISTEN: &str = "127.0.0.1:7878";
+
+/// The long-running modes, selected by the first argument instead of an input file.
+#[derive(Clone, Copy)]
+enum Service {
+    /// `serve`: JSON over HTTP.
+    Http,
+    /// `listen`: CSV rows over plain TCP.
+    Tcp,
+}
+
+/// The read-only modes, which replay an input file to report on it instead of saving state.
+#[derive(Clone, Copy, PartialEq)]
+enum Report {
+    /// `balance`: a client's accounts as of an input row or transaction.
+    Balance,
+    /// `statement`: every accepted row of one client or of all clients, with running balances.
+    Statement,
+    /// `reconcile`: differences between the accounts and a partner's expected accounts.
+    Reconcile,
+    /// `audit`: invariant violations in a saved state.
+    Audit,
+}
+
+/// Where and how the final account states are written.
+struct Output {
+    order: Option<OutputOrder>,
+    format: DataFormat,
+    path: Option<PathBuf>,
+}
+
+impl Output {
+    /// Opens the output file, or stdout.
+    fn writer(&self) -> io::Result<Box<dyn Write>> {
+        Ok(match &self.path {
+            Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+            None => Box::new(io::stdout()),
+        })
+    }
+}
+
+/// The command line arguments, parsed but not yet checked against each other.
+pub struct Args {
+    service: Option<Service>,
+    report: Option<Report>,
+    /// The input file, or for `audit` the state file.
+    file_path: Option<String>,
+    config: EngineConfig,
+    threads: usize,
+    state_in: Option<PathBuf>,
+    state_out: Option<PathBuf>,
+    wal: Option<PathBuf>,
+    rejections: Option<PathBuf>,
+    events: Option<PathBuf>,
+    invariant_checks: bool,
+    input_format: Option<DataFormat>,
+    output: Output,
+    listen: Option<String>,
+    client: Option<u16>,
+    as_of: Option<AsOf>,
+    checkpoints: Option<PathBuf>,
+    checkpoint_interval: Option<u64>,
+    expected: Option<PathBuf>,
+    tolerance: Decimal,
+    columns: Vec<(String, String)>,
+}
+
+fn usage() -> AppError {
+    AppError::Usage(
+        "Usage: payment-engine [--allow-redispute] [--dispute-window DAYS] [--fees FILE] \
+         [--credit-limits FILE] [--over-limit allow|reject|lock] [--currencies] [--status-column] \
+         [--sort client|total|locked] [--threads N] \
+         [--state-in FILE] [--state-out FILE] [--wal FILE] [--rejections FILE] [--events FILE] \
+         [--check-invariants] [--input-format csv|jsonl] [--output FILE] [--output-format csv|jsonl] <input_file>\n       \
+         payment-engine serve|listen [--listen ADDR] [--state-in FILE] [--state-out FILE] [engine and output flags]\n       \
+         payment-engine balance --client ID (--row N | --tx ID) [--checkpoints DIR [--checkpoint-every N]] \
+         [--state-in FILE] [engine and output flags] <input_file>\n       \
+         payment-engine statement [--client ID] [--state-in FILE] [engine and output flags] <input_file>\n       \
+         payment-engine reconcile --expected FILE [--tolerance AMOUNT] [--map FIELD=COLUMN]... \
+         [--state-in FILE] [engine and output flags] [input_file]\n       \
+         payment-engine audit <state_file>"
+            .to_string(),
+    )
+}
+
+impl Args {
+    /// Parses the arguments that follow the program name. Fee and credit limit files are
+    /// loaded here, so a broken one is reported before anything runs.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, AppError> {
+        let mut args = args.into_iter().peekable();
+        let service = match args.peek().map(String::as_str) {
+            Some("serve") => Some(Service::Http),
+            Some("listen") => Some(Service::Tcp),
+            _ => None,
+        };
+        let report = match args.peek().map(String::as_str) {
+            Some("balance") => Some(Report::Balance),
+            Some("statement") => Some(Report::Statement),
+            Some("reconcile") => Some(Report::Reconcile),
+            Some("audit") => Some(Report::Audit),
+            _ => None,
+        };
+        if service.is_some() || report.is_some() {
+            args.next();
+        }
+        let mut parsed = Args {
+            service,
+            report,
+            file_path: None,
+            config: EngineConfig::default(),
+            threads: 1,
+            state_in: None,
+            state_out: None,
+            wal: None,
+            rejections: None,
+            events: None,
+            invariant_checks: false,
+            input_format: None,
+            output: Output {
+                order: None,
+                format: DataFormat::default(),
+                path: None,
+            },
+            listen: None,
+            client: None,
+            as_of: None,
+            checkpoints: None,
+            checkpoint_interval: None,
+            expected: None,
+            tolerance: Decimal::ZERO,
+            columns: Vec::new(),
+        };
+        let mut output_format = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--allow-redispute" => parsed.config.redispute = RedisputePolicy::Allow,
+                "--currencies" => parsed.config.currencies = true,
+                "--status-column" => parsed.config.status_column = true,
+                "--dispute-window" => {
+                    let seconds = args
+                        .next()
+                        .and_then(|n| n.parse::<u64>().ok())
+                        .and_then(|days| days.checked_mul(24 * 60 * 60));
+                    parsed.config.dispute_window = Some(Duration::from_secs(seconds.ok_or_else(usage)?));
+                }
+                "--fees" => parsed.config.fees = FeeSchedule::load(Path::new(&args.next().ok_or_else(usage)?))?,
+                "--credit-limits" => {
+                    parsed.config.credit_limits = CreditLimits::load(Path::new(&args.next().ok_or_else(usage)?))?
+                }
+                "--over-limit" => {
+                    parsed.config.over_limit = match args.next().as_deref() {
+                        Some("allow") => OverLimitPolicy::Allow,
+                        Some("reject") => OverLimitPolicy::Reject,
+                        Some("lock") => OverLimitPolicy::Lock,
+                        _ => return Err(usage()),
+                    }
+                }
+                "--sort" => {
+                    parsed.output.order = Some(match args.next().as_deref() {
+                        Some("client") => OutputOrder::ClientId,
+                        Some("total") => OutputOrder::Total,
+                        Some("locked") => OutputOrder::Locked,
+                        _ => return Err(usage()),
+                    })
+                }
+                "--threads" => {
+                    parsed.threads = match args.next().and_then(|n| n.parse().ok()) {
+                        Some(n) if n > 0 => n,
+                        _ => return Err(usage()),
+                    }
+                }
+                "--listen" if service.is_some() => parsed.listen = Some(args.next().ok_or_else(usage)?),
+                "--client" if report.is_some() => {
+                    parsed.client = Some(args.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?)
+                }
+                "--row" if report == Some(Report::Balance) && parsed.as_of.is_none() => {
+                    parsed.as_of = Some(AsOf::Row(args.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?))
+                }
+                "--tx" if report == Some(Report::Balance) && parsed.as_of.is_none() => {
+                    parsed.as_of = Some(AsOf::Tx(args.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?))
+                }
+                "--checkpoints" if report == Some(Report::Balance) => {
+                    parsed.checkpoints = Some(PathBuf::from(args.next().ok_or_else(usage)?))
+                }
+                "--checkpoint-every" if report == Some(Report::Balance) => {
+                    parsed.checkpoint_interval = Some(args.next().and_then(|n| n.parse().ok()).ok_or_else(usage)?)
+                }
+                "--expected" if report == Some(Report::Reconcile) => {
+                    parsed.expected = Some(PathBuf::from(args.next().ok_or_else(usage)?))
+                }
+                "--tolerance" if report == Some(Report::Reconcile) => {
+                    parsed.tolerance = match args.next().and_then(|n| n.parse::<Decimal>().ok()) {
+                        Some(amount) if amount >= Decimal::ZERO => amount,
+                        _ => return Err(usage()),
+                    }
+                }
+                "--map" if report == Some(Report::Reconcile) => {
+                    let mapping = args.next().ok_or_else(usage)?;
+                    let (field, column) = mapping.split_once('=').ok_or_else(usage)?;
+                    parsed.columns.push((field.to_string(), column.to_string()));
+                }
+                "--state-in" => parsed.state_in = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--state-out" => parsed.state_out = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--wal" => parsed.wal = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--rejections" => parsed.rejections = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--events" => parsed.events = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--check-invariants" => parsed.invariant_checks = true,
+                "--input-format" => {
+                    parsed.input_format =
+                        Some(args.next().as_deref().and_then(DataFormat::from_name).ok_or_else(usage)?)
+                }
+                "--output" => parsed.output.path = Some(PathBuf::from(args.next().ok_or_else(usage)?)),
+                "--output-format" => {
+                    output_format = Some(args.next().as_deref().and_then(DataFormat::from_name).ok_or_else(usage)?)
+                }
+                _ if arg.starts_with("--") || parsed.file_path.is_some() => return Err(usage()),
+                _ => parsed.file_path = Some(arg),
+            }
+        }
+        parsed.output.format = output_format
+            .or_else(|| parsed.output.path.as_deref().map(DataFormat::from_path))
+            .unwrap_or_default();
+        Ok(parsed)
+    }
+
+    /// Whether any flag asks for something to be logged, reported or saved.
+    fn writes(&self) -> bool {
+        let logs = self.wal.is_some() || self.rejections.is_some() || self.events.is_some();
+        logs || self.threads > 1 || self.state_out.is_some()
+    }
+}
+
+/// Runs the mode the arguments select. Returns the exit code of a run that completed, which
+/// is `FINDINGS_EXIT_CODE` when `reconcile` or `audit` found something.
+pub fn run(args: Args) -> Result<ExitCode, AppError> {
+    if let Some(service) = args.service {
+        // A service takes its rows from the network and applies them one at a time, unlogged.
+        let file_flags = args.file_path.is_some() || args.input_format.is_some() || args.rejections.is_some();
+        if file_flags || args.events.is_some() || args.threads > 1 || args.wal.is_some() {
+            return Err(usage());
+        }
+        let mut engine = match load_state(args.state_in.as_deref(), &args.config)? {
+            Some(snapshot) => PaymentEngine::from_snapshot(snapshot, args.config),
+            None => PaymentEngine::with_config(args.config),
+        };
+        engine.set_invariant_checks(args.invariant_checks);
+        let state_out = args.state_out.as_deref();
+        match service {
+            Service::Http => service::serve_http(
+                args.listen.as_deref().unwrap_or(DEFAULT_HTTP_LISTEN),
+                engine,
+                &args.output,
+                state_out,
+            )?,
+            Service::Tcp => service::listen_tcp(
+                args.listen.as_deref().unwrap_or(DEFAULT_TCP_LISTEN),
+                engine,
+                &args.output,
+                state_out,
+            )?,
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if args.report == Some(Report::Audit) {
+        // The state to audit is given in place of the input file, and nothing is written or saved.
+        let reads = args.state_in.is_some() || args.input_format.is_some() || args.invariant_checks;
+        if args.writes() || reads || args.output.path.is_some() {
+            return Err(usage());
+        }
+        return report::audit_snapshot(Path::new(&args.file_path.ok_or_else(usage)?), args.config);
+    }
+    if let Some(report) = args.report {
+        // A report only looks at its inputs, so there is nothing to log, report, check or save.
+        if args.writes() || args.invariant_checks {
+            return Err(usage());
+        }
+        let engine = match load_state(args.state_in.as_deref(), &args.config)? {
+            Some(snapshot) => PaymentEngine::from_snapshot(snapshot, args.config),
+            None => PaymentEngine::with_config(args.config),
+        };
+        let input = args.file_path.map(PathBuf::from).map(|path| {
+            let format = args.input_format.unwrap_or_else(|| DataFormat::from_path(&path));
+            (path, format)
+        });
+        let output = &args.output;
+        return match (report, input, args.client, args.as_of, args.expected) {
+            (Report::Balance, Some((path, format)), Some(client), Some(as_of), None) => {
+                let checkpoints = match (args.checkpoints, args.checkpoint_interval) {
+                    (Some(dir), interval) => {
+                        match Checkpoints::open(&dir, interval.unwrap_or(DEFAULT_CHECKPOINT_INTERVAL)) {
+                            Ok(checkpoints) => Some(checkpoints),
+                            Err(e @ HistoryError::ZeroInterval) => return Err(AppError::Usage(e.to_string())),
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                    (None, Some(_)) => return Err(usage()),
+                    (None, None) => None,
+                };
+                report::query_balance(engine, &path, format, client, as_of, checkpoints, output)?;
+                Ok(ExitCode::SUCCESS)
+            }
+            (Report::Statement, Some((path, format)), client, None, None) => {
+                report::write_statements(engine, &path, format, client, output)?;
+                Ok(ExitCode::SUCCESS)
+            }
+            (Report::Reconcile, input, None, None, Some(path)) => {
+                let expected = ExpectedAccounts::load(&path, &args.columns)?;
+                report::reconcile_accounts(engine, input, &expected, args.tolerance, output)
+            }
+            _ => Err(usage()),
+        };
+    }
+    batch::run(args)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Loads the state to continue from, if one was given. Balances in named currencies need
+/// `--currencies`, since the account output would otherwise not tell them apart.
+fn load_state(path: Option<&Path>, config: &EngineConfig) -> Result<Option<Snapshot>, AppError> {
+    let Some(path) = path else { return Ok(None) };
+    let snapshot = Snapshot::load(path)?;
+    if !config.currencies && snapshot.accounts.iter().any(|account| !account.currency.is_unspecified()) {
+        return Err(AppError::Usage(
+            "The saved state holds balances in named currencies and needs --currencies".to_string(),
+        ));
+    }
+    Ok(Some(snapshot))
+}
+
+/// Writes the account states to the output file, or stdout, as CSV or JSON Lines.
+fn write_accounts(engine: &PaymentEngine, output: &Output) -> Result<(), AppError> {
+    write_records(engine.output_records(output.order.unwrap_or_default()), output)
+}
+
+/// Writes output rows to the output file, or stdout, as CSV or JSON Lines.
+fn write_records(records: Vec<OutputRecord>, output: &Output) -> Result<(), AppError> {
+    let writer = output.writer()?;
+    match output.format {
+        DataFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for record in records {
+                wtr.serialize(record)?;
+            }
+            wtr.flush()?;
+        }
+        DataFormat::JsonLines => {
+            let mut writer = writer;
+            for record in records {
+                serde_json::to_writer(&mut writer, &record).map_err(io::Error::from)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Args, AppError> {
+        Args::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    fn is_usage<T>(result: Result<T, AppError>) -> bool {
+        matches!(result, Err(AppError::Usage(_)))
+    }
+
+    #[test]
+    fn test_parse_reads_flags_and_input_file() {
+        let args = parse("--currencies --threads 4 --output out.jsonl --sort total in.csv").unwrap();
+        assert!(args.config.currencies);
+        assert_eq!(args.threads, 4);
+        assert_eq!(args.output.format, DataFormat::JsonLines);
+        assert_eq!(args.output.order, Some(OutputOrder::Total));
+        assert_eq!(args.file_path.as_deref(), Some("in.csv"));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_flags() {
+        assert!(is_usage(parse("--threads 0 in.csv")));
+        assert!(is_usage(parse("--unknown in.csv")));
+        assert!(is_usage(parse("in.csv other.csv")));
+        // Mode flags only belong to their mode.
+        assert!(is_usage(parse("--listen 127.0.0.1:0 in.csv")));
+        assert!(is_usage(parse("statement --row 1 in.csv")));
+    }
+
+    #[test]
+    fn test_run_rejects_flags_the_mode_cannot_honour() {
+        assert!(is_usage(run(parse("serve --threads 2").unwrap())));
+        assert!(is_usage(run(parse("audit --state-out out.bin state.bin").unwrap())));
+        assert!(is_usage(run(parse("statement --check-invariants in.csv").unwrap())));
+        assert!(is_usage(run(parse("--wal run.wal in.csv").unwrap())));
+        assert!(is_usage(run(parse("balance --client 1 --row 1 --checkpoints dir --checkpoint-every 0 in.csv").unwrap())));
+    }
+}
@@ -0,0 +1,287 @@
+//! The default mode: applies an input file and writes the final account states.
+
+use super::{load_state, usage, write_accounts, Args, Output};
+use payment_engine::{
+    audit, AppError, DataFormat, EngineError, EventSink, InputRecord, JsonLinesSink, ParallelEngine, PaymentEngine,
+    RecordReader, Rejection, RejectionWriter, WalEngine, WalError,
+};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Where a record came from, kept so it can be reported if the engine rejects it.
+struct Row {
+    /// Data row number, counted from 1 and excluding the header.
+    index: u64,
+    /// Line number in the input file.
+    line: u64,
+    /// The raw row, only captured when a rejections report was requested.
+    raw: String,
+    client: u16,
+    tx: u32,
+}
+
+/// Reports rejected rows on stderr and, optionally, in a rejections file, and logs the admin
+/// actions applied, the replayed rows skipped and the fees collected during the run.
+struct Reporter {
+    writer: Option<RejectionWriter<BufWriter<File>>>,
+    /// Admin actions already in the engine's log when the run started.
+    admin_logged: usize,
+}
+
+impl Reporter {
+    fn engine_error(&mut self, row: Row, e: &EngineError) -> Result<(), AppError> {
+        eprintln!("Warning: {}", e);
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write(&Rejection::from_engine_error(row.line, row.raw, row.client, row.tx, e))?;
+        }
+        Ok(())
+    }
+
+    fn parse_error(&mut self, rejection: Rejection) -> Result<(), AppError> {
+        // If a row is malformed, print an error to stderr and continue.
+        eprintln!("Warning: Failed to parse a record, skipping. Error: {}", rejection.message);
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write(&rejection)?;
+        }
+        Ok(())
+    }
+
+    fn admin_actions(&mut self, engine: &PaymentEngine) {
+        for action in engine.admin_actions().iter().skip(self.admin_logged) {
+            eprintln!("Admin: {}", action);
+        }
+        self.admin_logged = engine.admin_actions().len();
+    }
+
+    fn replays(&self, engine: &PaymentEngine) {
+        if engine.replays() > 0 {
+            eprintln!("Skipped {} replayed rows that were already applied", engine.replays());
+        }
+    }
+
+    fn fee_revenue(&self, engine: &PaymentEngine) {
+        for (currency, amount) in engine.fee_revenue() {
+            if currency.is_unspecified() {
+                eprintln!("Fee revenue: {:.4}", amount);
+            } else {
+                eprintln!("Fee revenue: {:.4} {}", amount, currency);
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), AppError> {
+        if let Some(mut writer) = self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The ways the command line can drive the engine.
+enum Runner {
+    /// One engine on the current thread.
+    Single(PaymentEngine),
+    /// Worker engines sharded by client ID.
+    Parallel(ParallelEngine<Row>),
+    /// One engine whose accepted records are written ahead to a log.
+    Logged(WalEngine),
+}
+
+impl Runner {
+    /// Applies a record. Rejections are reported and processing continues, as per the
+    /// requirements; only I/O failures and invariant violations abort the run.
+    fn process(&mut self, row: Row, record: InputRecord, reporter: &mut Reporter) -> Result<(), AppError> {
+        match self {
+            // Worker rejections are collected and reported once all shards are merged.
+            Runner::Parallel(parallel) => parallel.process(row, record),
+            Runner::Single(engine) => match engine.process(record) {
+                Err(EngineError::InvariantViolated(violation)) => return Err(violation.into()),
+                Err(e) => reporter.engine_error(row, &e)?,
+                Ok(()) => {}
+            },
+            Runner::Logged(logged) => match logged.process(row.index, record) {
+                Err(WalError::Engine(EngineError::InvariantViolated(violation))) => return Err(violation.into()),
+                Err(WalError::Engine(e)) => reporter.engine_error(row, &e)?,
+                result => result?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Writes the final account states to stdout and persists the state to `state_out`, if given.
+    /// A logged run checkpoints instead, which also empties the write-ahead log.
+    fn finish(
+        self,
+        output: &Output,
+        state_out: Option<&Path>,
+        reporter: &mut Reporter,
+    ) -> Result<(), AppError> {
+        match self {
+            Runner::Single(mut engine) => {
+                engine.flush_events()?;
+                check_final_state(&engine)?;
+                reporter.admin_actions(&engine);
+                reporter.replays(&engine);
+                reporter.fee_revenue(&engine);
+                write_accounts(&engine, output)?;
+                if let Some(path) = state_out {
+                    engine.snapshot().save(path)?;
+                }
+            }
+            Runner::Parallel(parallel) => {
+                let outcome = parallel.finish();
+                for (row, e) in outcome.errors {
+                    if let EngineError::InvariantViolated(violation) = e {
+                        return Err(violation.into());
+                    }
+                    reporter.engine_error(row, &e)?;
+                }
+                Runner::Single(outcome.engine).finish(output, state_out, reporter)?;
+            }
+            Runner::Logged(mut logged) => {
+                logged.flush_events()?;
+                check_final_state(logged.engine())?;
+                reporter.admin_actions(logged.engine());
+                reporter.replays(logged.engine());
+                reporter.fee_revenue(logged.engine());
+                write_accounts(logged.engine(), output)?;
+                if let Some(path) = state_out {
+                    logged.checkpoint(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Audits the whole state at the end of a run with invariant checks, which also covers the
+/// parts no record touched, such as a loaded state that was inconsistent to begin with. A state
+/// that fails is neither written nor saved.
+fn check_final_state(engine: &PaymentEngine) -> Result<(), AppError> {
+    if !engine.invariant_checks() {
+        return Ok(());
+    }
+    let violations = audit(engine);
+    for violation in &violations {
+        eprintln!("Invariant violated: {}", violation);
+    }
+    match violations.len() {
+        0 => Ok(()),
+        n => Err(AppError::AuditFailed(n)),
+    }
+}
+
+/// Applies the input file, reporting rejected rows, then writes the final account states and
+/// saves the state to `--state-out`, if given.
+pub(super) fn run(args: Args) -> Result<(), AppError> {
+    let file_path = PathBuf::from(args.file_path.ok_or_else(usage)?);
+    // Formats given by flag win; otherwise they follow the file extension.
+    let input_format = args.input_format.unwrap_or_else(|| DataFormat::from_path(&file_path));
+    if args.wal.is_some() && (args.state_out.is_none() || args.threads > 1) {
+        // The log is emptied by checkpointing into --state-out, and only one thread appends to it.
+        return Err(AppError::Usage(
+            "--wal requires --state-out and cannot be combined with --threads".to_string(),
+        ));
+    }
+    if args.events.is_some() && args.threads > 1 {
+        // Shards apply records concurrently, so there is no single order to emit events in.
+        return Err(AppError::Usage("--events cannot be combined with --threads".to_string()));
+    }
+
+    // Initialize the payment engine, continuing from a previous run's state if one was given.
+    // With a write-ahead log, records accepted before a crash are replayed on top of that state
+    // and the input rows they came from are skipped.
+    let config = args.config;
+    let snapshot = load_state(args.state_in.as_deref(), &config)?;
+    let admin_logged = snapshot.as_ref().map_or(0, |s| s.admin_actions.len());
+    let mut resume_after = 0;
+    // Domain events go to a JSON Lines file, if asked for.
+    let event_sink: Option<Box<dyn EventSink>> = match &args.events {
+        Some(path) => Some(Box::new(JsonLinesSink::new(BufWriter::new(File::create(path)?)))),
+        None => None,
+    };
+    let mut runner = match &args.wal {
+        Some(path) => {
+            let (mut logged, recovery) = WalEngine::recover(snapshot, config, path)?;
+            if let Some(sink) = event_sink {
+                logged.set_event_sink(sink);
+            }
+            logged.set_invariant_checks(args.invariant_checks);
+            if recovery.truncated_bytes > 0 {
+                eprintln!(
+                    "Warning: Truncated a torn {}-byte entry from the write-ahead log",
+                    recovery.truncated_bytes
+                );
+            }
+            if let Some(row) = recovery.last_row {
+                eprintln!(
+                    "Recovered {} records from the write-ahead log; resuming after input row {}",
+                    recovery.replayed, row
+                );
+                resume_after = row;
+            }
+            Runner::Logged(logged)
+        }
+        None => {
+            let mut engine = match snapshot {
+                Some(snapshot) => PaymentEngine::from_snapshot(snapshot, config),
+                None => PaymentEngine::with_config(config),
+            };
+            if let Some(sink) = event_sink {
+                engine.set_event_sink(sink);
+            }
+            engine.set_invariant_checks(args.invariant_checks);
+            if args.threads > 1 {
+                Runner::Parallel(ParallelEngine::from_engine(engine, args.threads))
+            } else {
+                Runner::Single(engine)
+            }
+        }
+    };
+
+    // Open the rejections report, picking CSV or JSON Lines from the file extension.
+    let mut reporter = Reporter {
+        writer: match &args.rejections {
+            Some(path) => Some(RejectionWriter::new(
+                BufWriter::new(File::create(path)?),
+                DataFormat::from_path(path),
+            )),
+            None => None,
+        },
+        admin_logged,
+    };
+
+    // Create a streaming reader over the input, keeping the raw rows for the rejections report.
+    let rows = RecordReader::from_path(&file_path, input_format)?
+        .capture_raw(reporter.writer.is_some());
+
+    // Process each record. Rows are numbered from 1, excluding the header and comments.
+    for (index, row) in rows.enumerate() {
+        let index = index as u64 + 1;
+        if index <= resume_after {
+            continue;
+        }
+        let row = row?;
+        match row.record {
+            Ok(record) => {
+                let row = Row {
+                    index,
+                    line: row.line,
+                    raw: row.raw,
+                    client: record.client_id,
+                    tx: record.tx_id,
+                };
+                runner.process(row, record, &mut reporter)?
+            }
+            Err(failure) => {
+                reporter.parse_error(Rejection::from_parse_failure(row.line, row.raw, failure))?
+            }
+        }
+    }
+
+    // After processing all transactions, write the final account states to stdout
+    // and persist the complete state so the next run can pick up where this one stopped.
+    runner.finish(&args.output, args.state_out.as_deref(), &mut reporter)?;
+    reporter.finish()
+}
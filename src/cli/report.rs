@@ -0,0 +1,116 @@
+//! The read-only modes: `balance`, `statement`, `reconcile` and `audit`.
+
+use super::{write_records, Output};
+use payment_engine::{
+    audit, reconcile, replay_until, write_discrepancies, write_statement, AppError, AsOf, Checkpoints, DataFormat,
+    EngineConfig, ExpectedAccounts, HistoryError, Issue, OutputRecord, PaymentEngine, RecordReader, Snapshot,
+    StatementGenerator,
+};
+use rust_decimal::Decimal;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+/// The exit code of `reconcile` and `audit` when they found differences or violations. Errors
+/// exit with 1, as in every other mode.
+const FINDINGS_EXIT_CODE: u8 = 2;
+
+/// Replays the input up to `as_of`, from the nearest checkpoint if checkpoints are kept, and
+/// writes the client's accounts as they stood then.
+pub(super) fn query_balance(
+    mut engine: PaymentEngine,
+    file_path: &Path,
+    input_format: DataFormat,
+    client: u16,
+    as_of: AsOf,
+    checkpoints: Option<Checkpoints>,
+    output: &Output,
+) -> Result<(), AppError> {
+    let row = match checkpoints {
+        Some(mut checkpoints) => {
+            let (replayed, row) = checkpoints.replay_until(engine, file_path, input_format, as_of)?;
+            engine = replayed;
+            row
+        }
+        None => replay_until(&mut engine, RecordReader::from_path(file_path, input_format)?, as_of)?,
+    };
+    let records: Vec<OutputRecord> = engine
+        .output_records(output.order.unwrap_or_default())
+        .into_iter()
+        .filter(|record| record.client_id == client)
+        .collect();
+    if records.is_empty() {
+        return Err(HistoryError::AccountNotFound(client, row).into());
+    }
+    eprintln!("Balances of client {} as of input row {}", client, row);
+    write_records(records, output)
+}
+
+/// Applies the input and writes the statement of `client`, or of every client.
+pub(super) fn write_statements(
+    engine: PaymentEngine,
+    file_path: &Path,
+    input_format: DataFormat,
+    client: Option<u16>,
+    output: &Output,
+) -> Result<(), AppError> {
+    let mut generator = StatementGenerator::new(engine, client);
+    for row in RecordReader::from_path(file_path, input_format)? {
+        // Rejected rows changed nothing, so they have no place in a statement.
+        if let Ok(record) = row?.record {
+            let _ = generator.process(record);
+        }
+    }
+    let (lines, _) = generator.finish();
+    write_statement(&lines, output.writer()?, output.format)?;
+    Ok(())
+}
+
+/// Applies the input, if any, compares the accounts with the expected ones and writes the
+/// differences. Returns `FINDINGS_EXIT_CODE` if there are any.
+pub(super) fn reconcile_accounts(
+    mut engine: PaymentEngine,
+    input: Option<(PathBuf, DataFormat)>,
+    expected: &ExpectedAccounts,
+    tolerance: Decimal,
+    output: &Output,
+) -> Result<ExitCode, AppError> {
+    if let Some((path, format)) = input {
+        for row in RecordReader::from_path(&path, format)? {
+            // Rejected rows are a matter for the run's own report, not for reconciliation.
+            if let Ok(record) = row?.record {
+                let _ = engine.process(record);
+            }
+        }
+    }
+    let discrepancies = reconcile(&engine, expected, tolerance);
+    write_discrepancies(&discrepancies, output.writer()?, output.format)?;
+    if discrepancies.is_empty() {
+        eprintln!("Reconciled {} accounts: no differences", engine.accounts().count());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let count = |issue| discrepancies.iter().filter(|d| d.issue == issue).count();
+    eprintln!(
+        "Reconciliation failed: {} missing clients, {} extra clients, {} balance mismatches, {} locked mismatches",
+        count(Issue::MissingClient),
+        count(Issue::ExtraClient),
+        count(Issue::BalanceMismatch),
+        count(Issue::LockedMismatch),
+    );
+    Ok(ExitCode::from(FINDINGS_EXIT_CODE))
+}
+
+/// Loads a saved state and reports every invariant it violates. Returns
+/// `FINDINGS_EXIT_CODE` if there are any.
+pub(super) fn audit_snapshot(path: &Path, config: EngineConfig) -> Result<ExitCode, AppError> {
+    let engine = PaymentEngine::from_snapshot(Snapshot::load(path)?, config);
+    let violations = audit(&engine);
+    if violations.is_empty() {
+        eprintln!("Audited {} accounts: no violations", engine.accounts().count());
+        return Ok(ExitCode::SUCCESS);
+    }
+    for violation in &violations {
+        println!("{}", violation);
+    }
+    eprintln!("Audit found {} invariant violations", violations.len());
+    Ok(ExitCode::from(FINDINGS_EXIT_CODE))
+}
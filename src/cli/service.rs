@@ -0,0 +1,52 @@
+//! The long-running modes: `serve` and `listen`.
+
+use super::{write_accounts, Output};
+use payment_engine::{AppError, LineListener, PaymentEngine, Server};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::path::Path;
+
+/// Threads handling HTTP requests in `serve` mode.
+const SERVER_THREADS: usize = 4;
+
+/// Serves the engine over HTTP until SIGINT or SIGTERM, then writes the account states as a
+/// file run does and saves the state to `state_out`, if given.
+pub(super) fn serve_http(
+    listen: &str,
+    engine: PaymentEngine,
+    output: &Output,
+    state_out: Option<&Path>,
+) -> Result<(), AppError> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let server = Server::start(listen, engine, SERVER_THREADS)?;
+    if let Some(addr) = server.local_addr() {
+        eprintln!("Listening on http://{}", addr);
+    }
+    signals.forever().next();
+    let engine = server.shutdown();
+    write_accounts(&engine, output)?;
+    if let Some(path) = state_out {
+        engine.snapshot().save(path)?;
+    }
+    Ok(())
+}
+
+/// Applies CSV rows received over TCP until SIGINT or SIGTERM, then writes the account states
+/// as a file run does and saves the state to `state_out`, if given.
+pub(super) fn listen_tcp(
+    listen: &str,
+    engine: PaymentEngine,
+    output: &Output,
+    state_out: Option<&Path>,
+) -> Result<(), AppError> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    let listener = LineListener::start(listen, engine)?;
+    eprintln!("Listening for CSV rows on {}", listener.local_addr());
+    signals.forever().next();
+    let engine = listener.shutdown();
+    write_accounts(&engine, output)?;
+    if let Some(path) = state_out {
+        engine.snapshot().save(path)?;
+    }
+    Ok(())
+}
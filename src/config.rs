@@ -0,0 +1,49 @@
+use crate::fees::FeeSchedule;
+use crate::limits::CreditLimits;
+use std::time::Duration;
+
+/// Tunable business rules for a `PaymentEngine`, and the optional columns of its account output.
+#[derive(Debug, Clone, Default)]
+pub struct EngineConfig {
+    /// Whether a transaction whose dispute was resolved may be disputed again.
+    pub redispute: RedisputePolicy,
+    /// How long after a transaction it may still be disputed, measured between the input
+    /// timestamps of the transaction and the dispute. `None` disables the check.
+    pub dispute_window: Option<Duration>,
+    /// Fees charged alongside each row.
+    pub fees: FeeSchedule,
+    /// How far each client may overdraw.
+    pub credit_limits: CreditLimits,
+    /// What happens to a dispute that takes a balance past the client's credit limit.
+    pub over_limit: OverLimitPolicy,
+    /// Whether rows may name a currency. The account output has a `currency` column exactly
+    /// when they may, so its header never depends on the input.
+    pub currencies: bool,
+    /// Whether the account output has a `status` column with each account's admin status.
+    pub status_column: bool,
+}
+
+/// Policy for disputing a transaction that has already been resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum RedisputePolicy {
+    /// A resolved transaction is final and further disputes are rejected.
+    #[default]
+    Reject,
+    /// A resolved transaction may be disputed again.
+    Allow,
+}
+
+/// Policy for a dispute that would take `available` below the client's credit limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OverLimitPolicy {
+    /// The dispute goes through, as it would without a limit.
+    #[default]
+    Allow,
+    /// The dispute is rejected.
+    Reject,
+    /// The dispute goes through and the account is locked.
+    ///
+    /// A locked account rejects resolves, while a chargeback still goes through. To settle the
+    /// dispute in the client's favour, an operator first sends an `unlock` row, then the resolve.
+    Lock,
+}
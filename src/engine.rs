@@ -1,6 +1,13 @@
+use crate::config::{EngineConfig, OverLimitPolicy};
 use crate::error::EngineError;
+use crate::events::{Event, EventLog, EventSink};
+use crate::invariants::Probe;
 use crate::models::{
-    Account, InputRecord, OutputRecord, TransactionRecord, TransactionStatus, TransactionType,
+    Account, AccountStatus, AdminAction, Currency, InputRecord, OutputOrder, OutputRecord, TransactionDirection, TransactionRecord,
+    TransactionStatus, TransactionType,
+};
+use crate::snapshot::{
+    AccountSnapshot, FeeRevenueSnapshot, Snapshot, TransactionSnapshot, SNAPSHOT_VERSION,
 };
 use rust_decimal::Decimal;
 use std::collections::hash_map::Entry;
@@ -10,46 +17,425 @@ use std::io::Write;
 /// The core of the payment processing system.
 /// It maintains the state of all client accounts and transactions.
 pub struct PaymentEngine {
-    /// Stores the state of each client account, keyed by client ID.
-    accounts: HashMap<u16, Account>,
-    /// Stores deposit transactions that can be disputed, keyed by transaction ID.
+    /// Stores the state of each client account, keyed by client ID and currency.
+    /// A client holds a separate balance in every currency it has transacted in.
+    accounts: HashMap<(u16, Currency), Account>,
+    /// Stores deposits and withdrawals that can be disputed, keyed by transaction ID.
+    /// A transfer is stored here as its sending leg.
     transactions: HashMap<u32, TransactionRecord>,
+    /// Stores the receiving leg of each transfer, under the transfer's transaction ID, so that
+    /// either client can dispute its own side.
+    transfer_credits: HashMap<u32, TransactionRecord>,
+    /// Every admin action applied so far, in order.
+    admin_actions: Vec<AdminAction>,
+    /// Fees collected so far, per currency.
+    fee_revenue: HashMap<Currency, Decimal>,
+    /// Replayed rows skipped since the engine was created or restored.
+    replays: u64,
+    /// Business rules the engine was configured with.
+    config: EngineConfig,
+    /// Where domain events go, if anywhere.
+    events: Option<EventLog>,
+    /// Whether every record is checked against the invariants once it was applied.
+    invariant_checks: bool,
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PaymentEngine {
     /// Creates a new, empty payment engine.
     pub fn new() -> Self {
+        Self::with_config(EngineConfig::default())
+    }
+
+    /// Creates a new, empty payment engine with the given business rules.
+    pub fn with_config(config: EngineConfig) -> Self {
         Self {
             accounts: HashMap::new(),
             transactions: HashMap::new(),
+            transfer_credits: HashMap::new(),
+            admin_actions: Vec::new(),
+            fee_revenue: HashMap::new(),
+            replays: 0,
+            config,
+            events: None,
+            invariant_checks: false,
         }
     }
 
     /// Processes a single transaction record, updating the engine's state.
     /// Returns a specific error if the transaction is invalid.
     pub fn process(&mut self, record: InputRecord) -> Result<(), EngineError> {
+        let (tx, client) = (record.tx_id, record.client_id);
+        let probe = self.invariant_checks.then(|| Probe::capture(self, &record));
+        let mut result = self.apply(record);
+        // A violating record is undone, so that its rejection is true and its events are dropped.
+        if let Some(Err(violation)) = probe.map(|probe| probe.check(self)) {
+            result = Err(EngineError::InvariantViolated(violation));
+        }
+        if let Some(events) = &mut self.events {
+            match &result {
+                Ok(()) => events.commit(),
+                Err(e) => events.reject(Event::TransactionRejected {
+                    tx,
+                    client,
+                    error: e.kind().to_string(),
+                    message: e.to_string(),
+                }),
+            }
+        }
+        result
+    }
+
+    fn apply(&mut self, record: InputRecord) -> Result<(), EngineError> {
+        self.ensure_currency_enabled(&record)?;
         match record.transaction_type {
             TransactionType::Deposit => self.handle_deposit(record),
             TransactionType::Withdrawal => self.handle_withdrawal(record),
+            TransactionType::Transfer => self.handle_transfer(record),
             TransactionType::Dispute => self.handle_dispute(record),
             TransactionType::Resolve => self.handle_resolve(record),
             TransactionType::Chargeback => self.handle_chargeback(record),
+            TransactionType::Unlock | TransactionType::Freeze | TransactionType::Close => {
+                let action = self.apply_admin(&record)?;
+                self.admin_actions.push(action);
+                Ok(())
+            }
         }
     }
 
-    /// Writes the final state of all accounts to a CSV writer.
+    /// Sends domain events to `sink` from now on: the effects of every accepted record, and
+    /// every rejection. Replaces any sink set before.
+    pub fn set_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.events = Some(EventLog::new(sink));
+    }
+
+    /// Stops emitting events and returns the sink they went to.
+    pub fn take_event_sink(&mut self) -> Option<Box<dyn EventSink>> {
+        self.events.take().map(EventLog::into_sink)
+    }
+
+    /// Flushes the event sink, reporting the first event that could not be written.
+    pub fn flush_events(&mut self) -> std::io::Result<()> {
+        self.events.as_mut().map_or(Ok(()), EventLog::flush)
+    }
+
+    /// Checks every record against the invariants of `invariants::audit` once it was applied, for
+    /// the accounts and transaction it touched. A record that breaks one is undone and fails with
+    /// `InvariantViolated`, like any other rejection. Since an accepted record should never break
+    /// one, a violation points at an engine bug or at a state that was already inconsistent.
+    pub fn set_invariant_checks(&mut self, enabled: bool) {
+        self.invariant_checks = enabled;
+    }
+
+    /// The business rules the engine was configured with.
+    pub(crate) fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Whether records are checked against the invariants.
+    pub fn invariant_checks(&self) -> bool {
+        self.invariant_checks
+    }
+
+    /// Returns the account of the given client in the unspecified currency, if it has been created.
+    pub fn account(&self, client_id: u16) -> Option<&Account> {
+        self.account_in(client_id, Currency::default())
+    }
+
+    /// Returns the given client's account in `currency`, if it has been created.
+    pub fn account_in(&self, client_id: u16, currency: Currency) -> Option<&Account> {
+        self.accounts.get(&(client_id, currency))
+    }
+
+    /// Iterates over every account with its client ID and currency, in no particular order.
+    /// Use `write_output` or `write_output_ordered` for a deterministic order.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, Currency, &Account)> {
+        self.accounts.iter().map(|((client_id, currency), account)| (*client_id, *currency, account))
+    }
+
+    /// Returns the ledger entry of a disputable transaction (deposit, withdrawal or the sending
+    /// leg of a transfer).
+    pub fn transaction(&self, tx_id: u32) -> Option<&TransactionRecord> {
+        self.transactions.get(&tx_id)
+    }
+
+    /// Every admin action applied so far, oldest first. The account output only shows their
+    /// effect on each account, so this is where an unlock can be seen after the fact.
+    pub fn admin_actions(&self) -> &[AdminAction] {
+        &self.admin_actions
+    }
+
+    /// The fees collected so far in each currency, ordered by currency.
+    pub fn fee_revenue(&self) -> Vec<(Currency, Decimal)> {
+        let mut revenue: Vec<(Currency, Decimal)> =
+            self.fee_revenue.iter().map(|(currency, amount)| (*currency, *amount)).collect();
+        revenue.sort();
+        revenue
+    }
+
+    /// The number of deposits and withdrawals skipped because they replayed a row that was
+    /// already applied, since the engine was created or restored.
+    pub fn replays(&self) -> u64 {
+        self.replays
+    }
+
+    /// Returns the receiving leg of a transfer.
+    pub fn transfer_credit(&self, tx_id: u32) -> Option<&TransactionRecord> {
+        self.transfer_credits.get(&tx_id)
+    }
+
+    /// Writes the final state of all accounts to a CSV writer, ordered by client ID.
     pub fn write_output<W: Write>(&self, wtr: &mut csv::Writer<W>) -> Result<(), csv::Error> {
-        for (client_id, account) in self.accounts.iter() {
-            let output_record = OutputRecord {
+        self.write_output_ordered(wtr, OutputOrder::ClientId)
+    }
+
+    /// Writes the final state of all accounts to a CSV writer in the given order.
+    /// The row order is deterministic: identical state always yields byte-identical output.
+    pub fn write_output_ordered<W: Write>(
+        &self,
+        wtr: &mut csv::Writer<W>,
+        order: OutputOrder,
+    ) -> Result<(), csv::Error> {
+        for output_record in self.output_records(order) {
+            wtr.serialize(output_record)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the final state of all accounts as JSON Lines, one object per account, in the given
+    /// order. Amounts keep the same four-decimal string formatting as the CSV output.
+    pub fn write_output_json_lines<W: Write>(
+        &self,
+        writer: &mut W,
+        order: OutputOrder,
+    ) -> std::io::Result<()> {
+        for output_record in self.output_records(order) {
+            serde_json::to_writer(&mut *writer, &output_record)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Builds the output rows for every account, sorted in the given order. The optional
+    /// columns follow the configuration alone, so the same flags always give the same header.
+    /// Rows show the state of each account, not the admin actions that led to it.
+    pub fn output_records(&self, order: OutputOrder) -> Vec<OutputRecord> {
+        let with_currency = self.config.currencies;
+        let with_status = self.config.status_column;
+        let with_credit = !self.config.credit_limits.is_empty();
+        let mut rows: Vec<OutputRecord> = self
+            .accounts
+            .iter()
+            .map(|((client_id, currency), account)| OutputRecord {
                 client_id: *client_id,
+                currency: with_currency.then_some(*currency),
                 available: account.available,
                 held: account.held,
                 total: account.total(),
+                credit_used: with_credit.then(|| account.credit_used()),
+                locked: account.locked,
+                status: with_status.then_some(account.status),
+            })
+            .collect();
+        match order {
+            OutputOrder::ClientId => rows.sort_by_key(|r| (r.client_id, r.currency)),
+            OutputOrder::Total => rows.sort_by_key(|r| (r.total, r.client_id, r.currency)),
+            OutputOrder::Locked => rows.sort_by_key(|r| (!r.locked, r.client_id, r.currency)),
+        }
+        rows
+    }
+
+    /// Captures the complete engine state: accounts, locked flags and the transaction ledger.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut accounts: Vec<AccountSnapshot> = self
+            .accounts
+            .iter()
+            .map(|((client_id, currency), account)| AccountSnapshot {
+                client: *client_id,
+                currency: *currency,
+                available: account.available,
+                held: account.held,
                 locked: account.locked,
+                status: account.status,
+            })
+            .collect();
+        accounts.sort_by_key(|a| (a.client, a.currency));
+        let mut transactions: Vec<TransactionSnapshot> = self
+            .transactions
+            .iter()
+            .chain(&self.transfer_credits)
+            .map(|(tx_id, tx)| TransactionSnapshot {
+                tx: *tx_id,
+                client: tx.client_id,
+                amount: tx.amount,
+                currency: tx.currency,
+                direction: tx.direction,
+                status: tx.status,
+                disputed: tx.disputed,
+                resolved: tx.resolved,
+                charged_back: tx.charged_back,
+                counterparty: tx.counterparty,
+                timestamp: tx.timestamp,
+                content_hash: tx.content_hash,
+            })
+            .collect();
+        // Both legs of a transfer share an ID but never a client.
+        transactions.sort_by_key(|t| (t.tx, t.client));
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            wal_lsn: 0,
+            accounts,
+            transactions,
+            admin_actions: self.admin_actions.clone(),
+            fee_revenue: self
+                .fee_revenue()
+                .into_iter()
+                .map(|(currency, amount)| FeeRevenueSnapshot { currency, amount })
+                .collect(),
+        }
+    }
+
+    /// Rebuilds an engine from a snapshot taken by `snapshot`, using the given business rules.
+    pub fn from_snapshot(snapshot: Snapshot, config: EngineConfig) -> Self {
+        let mut engine = Self::with_config(config);
+        for a in snapshot.accounts {
+            engine.accounts.insert(
+                (a.client, a.currency),
+                Account {
+                    available: a.available,
+                    held: a.held,
+                    locked: a.locked,
+                    status: a.status,
+                },
+            );
+        }
+        for mut t in snapshot.transactions {
+            if snapshot.version < 4 {
+                // Before partial disputes, every dispute-family row acted on the full amount.
+                match t.status {
+                    TransactionStatus::Normal => {}
+                    TransactionStatus::Disputed => t.disputed = t.amount,
+                    TransactionStatus::Resolved => t.resolved = t.amount,
+                    TransactionStatus::ChargedBack => t.charged_back = t.amount,
+                }
+            }
+            let ledger = match (t.counterparty, t.direction) {
+                (Some(_), TransactionDirection::Credit) => &mut engine.transfer_credits,
+                _ => &mut engine.transactions,
             };
-            wtr.serialize(output_record)?;
+            ledger.insert(
+                t.tx,
+                TransactionRecord {
+                    client_id: t.client,
+                    amount: t.amount,
+                    currency: t.currency,
+                    direction: t.direction,
+                    status: t.status,
+                    disputed: t.disputed,
+                    resolved: t.resolved,
+                    charged_back: t.charged_back,
+                    counterparty: t.counterparty,
+                    timestamp: t.timestamp,
+                    content_hash: t.content_hash,
+                },
+            );
         }
-        Ok(())
+        engine.admin_actions = snapshot.admin_actions;
+        engine.fee_revenue = snapshot.fee_revenue.into_iter().map(|f| (f.currency, f.amount)).collect();
+        engine
+    }
+
+    /// Iterates over the transaction ledger, in no particular order.
+    pub(crate) fn ledger(&self) -> impl Iterator<Item = (u32, &TransactionRecord)> {
+        self.transactions.iter().map(|(tx_id, tx)| (*tx_id, tx))
+    }
+
+    /// Iterates over the receiving legs of transfers, in no particular order.
+    pub(crate) fn transfer_credits(&self) -> impl Iterator<Item = (u32, &TransactionRecord)> {
+        self.transfer_credits.iter().map(|(tx_id, tx)| (*tx_id, tx))
+    }
+
+    /// Splits the engine into `shards` engines, each owning the clients where `client_id % shards` matches its index.
+    pub(crate) fn split(self, shards: usize) -> Vec<PaymentEngine> {
+        let mut parts: Vec<PaymentEngine> =
+            (0..shards).map(|_| Self::with_config(self.config.clone())).collect();
+        // Each shard checks the records, transfer legs and admin actions it applies itself.
+        for part in &mut parts {
+            part.invariant_checks = self.invariant_checks;
+        }
+        for ((client_id, currency), account) in self.accounts {
+            parts[client_id as usize % shards].accounts.insert((client_id, currency), account);
+        }
+        for (tx_id, tx) in self.transactions {
+            parts[tx.client_id as usize % shards].transactions.insert(tx_id, tx);
+        }
+        for (tx_id, tx) in self.transfer_credits {
+            parts[tx.client_id as usize % shards].transfer_credits.insert(tx_id, tx);
+        }
+        // The admin log is kept in order by the first shard; later actions are appended by
+        // the caller, which knows their order.
+        parts[0].admin_actions = self.admin_actions;
+        parts[0].fee_revenue = self.fee_revenue;
+        parts[0].replays = self.replays;
+        parts
+    }
+
+    /// Merges the state of another engine that handled a disjoint set of clients.
+    pub(crate) fn absorb(&mut self, shard: PaymentEngine) {
+        self.accounts.extend(shard.accounts);
+        self.transactions.extend(shard.transactions);
+        self.transfer_credits.extend(shard.transfer_credits);
+        self.admin_actions.extend(shard.admin_actions);
+        for (currency, amount) in shard.fee_revenue {
+            *self.fee_revenue.entry(currency).or_default() += amount;
+        }
+        self.replays += shard.replays;
+    }
+
+    /// Saves the parts of the state a record with ID `tx_id` can change, given the accounts it
+    /// may touch, so that `restore` can undo it.
+    pub(crate) fn save(&self, tx_id: u32, keys: &[(u16, Currency)]) -> Saved {
+        Saved {
+            accounts: keys.iter().map(|key| (*key, self.accounts.get(key).cloned())).collect(),
+            transaction: self.transactions.get(&tx_id).copied(),
+            transfer_credit: self.transfer_credits.get(&tx_id).copied(),
+            tx_id,
+            admin_actions: self.admin_actions.len(),
+            fee_revenue: self.fee_revenue.clone(),
+            replays: self.replays,
+        }
+    }
+
+    /// Puts back the state saved by `save`.
+    pub(crate) fn restore(&mut self, saved: Saved) {
+        for (key, account) in saved.accounts {
+            match account {
+                Some(account) => self.accounts.insert(key, account),
+                None => self.accounts.remove(&key),
+            };
+        }
+        for (ledger, tx) in [
+            (&mut self.transactions, saved.transaction),
+            (&mut self.transfer_credits, saved.transfer_credit),
+        ] {
+            match tx {
+                Some(tx) => ledger.insert(saved.tx_id, tx),
+                None => ledger.remove(&saved.tx_id),
+            };
+        }
+        self.admin_actions.truncate(saved.admin_actions);
+        self.fee_revenue = saved.fee_revenue;
+        self.replays = saved.replays;
+    }
+
+    /// Appends admin actions that were applied with `apply_admin`.
+    pub(crate) fn log_admin_actions(&mut self, actions: Vec<AdminAction>) {
+        self.admin_actions.extend(actions);
     }
 
     // --- Private Handler Methods ---
@@ -61,18 +447,30 @@ impl PaymentEngine {
         }
 
         if let Entry::Vacant(e) = self.transactions.entry(record.tx_id) {
-            let account = self.accounts.entry(record.client_id).or_default();
-            if account.locked {
-                return Err(EngineError::AccountLocked(record.client_id));
+            // Like a withdrawal, a deposit must cover its fee, so that it never lowers the balance.
+            let fee = self.config.fees.fee(record.transaction_type, record.client_id, amount);
+            if fee > amount {
+                return Err(EngineError::InsufficientFunds(record.client_id, amount));
             }
+            let account = self.accounts.entry((record.client_id, record.currency)).or_default();
+            account.ensure_active(record.client_id)?;
             account.deposit(amount);
-            e.insert(TransactionRecord {
+            Self::charge_fee(account, &mut self.fee_revenue, record.currency, fee);
+            Self::emit(&mut self.events, || Event::FundsDeposited {
+                tx: record.tx_id,
+                client: record.client_id,
+                currency: record.currency,
                 amount,
-                status: TransactionStatus::Normal,
+            });
+            Self::emit_fee(&mut self.events, record.tx_id, record.client_id, record.currency, fee);
+            e.insert(TransactionRecord {
+                timestamp: record.timestamp,
+                content_hash: Some(record.content_hash()),
+                ..TransactionRecord::new(record.client_id, amount, record.currency, TransactionDirection::Credit)
             });
             Ok(())
         } else {
-            Err(EngineError::DuplicateTransactionId(record.tx_id))
+            self.reused_id(&record)
         }
     }
 
@@ -82,34 +480,206 @@ impl PaymentEngine {
             return Err(EngineError::AmountNotPositive(record.tx_id));
         }
 
-        if let Some(account) = self.accounts.get_mut(&record.client_id) {
-            if account.locked {
-                return Err(EngineError::AccountLocked(record.client_id));
+        if let Entry::Vacant(e) = self.transactions.entry(record.tx_id) {
+            if let Some(account) = self.accounts.get_mut(&(record.client_id, record.currency)) {
+                account.ensure_active(record.client_id)?;
+                // The fee must be covered as well, and is taken in the same step.
+                let fee = self.config.fees.fee(record.transaction_type, record.client_id, amount);
+                let credit_limit = self.config.credit_limits.limit(record.client_id);
+                account
+                    .withdraw_on_credit(amount + fee, credit_limit)
+                    .map_err(|e| match e {
+                        EngineError::InsufficientFunds(_, _) => EngineError::InsufficientFunds(record.client_id, amount),
+                        _ => e,
+                    })?;
+                Self::collect_fee(&mut self.fee_revenue, record.currency, fee);
+                Self::emit(&mut self.events, || Event::FundsWithdrawn {
+                    tx: record.tx_id,
+                    client: record.client_id,
+                    currency: record.currency,
+                    amount,
+                });
+                Self::emit_fee(&mut self.events, record.tx_id, record.client_id, record.currency, fee);
+                e.insert(TransactionRecord {
+                    timestamp: record.timestamp,
+                    content_hash: Some(record.content_hash()),
+                    ..TransactionRecord::new(record.client_id, amount, record.currency, TransactionDirection::Debit)
+                });
             }
-            account
-                .withdraw(amount)
-                .map_err(|e| match e {
-                    EngineError::InsufficientFunds(_, _) => EngineError::InsufficientFunds(record.client_id, amount),
-                    _ => e,
-                })?;
-        }
-        // Note: If account doesn't exist, withdrawal implicitly fails, which is valid.
+            // Note: If account doesn't exist, withdrawal implicitly fails, which is valid.
+            Ok(())
+        } else {
+            self.reused_id(&record)
+        }
+    }
+
+    /// Handles a deposit or withdrawal whose ID is already in the ledger. An exact replay of
+    /// the row that created the entry, e.g. from a re-sent file, is a no-op; any other reuse of
+    /// the ID is rejected.
+    fn reused_id(&mut self, record: &InputRecord) -> Result<(), EngineError> {
+        let original = self.transactions.get(&record.tx_id).and_then(|tx| tx.content_hash);
+        if original == Some(record.content_hash()) {
+            self.replays += 1;
+            Ok(())
+        } else {
+            Err(EngineError::DuplicateTransactionId(record.tx_id))
+        }
+    }
+
+    fn handle_transfer(&mut self, record: InputRecord) -> Result<(), EngineError> {
+        let transfer = Self::validate_transfer(&record)?;
+        let receivable = self.ensure_receivable(transfer.to, transfer.currency);
+        // Every check happens before the debit, so the two legs are applied together or not at all.
+        self.debit_transfer(&transfer, receivable)?;
+        self.credit_transfer(&transfer);
+        Ok(())
+    }
+
+    /// Checks that a row only names a currency when currencies are enabled. Without them the
+    /// account output has no column to tell the balances of a client apart.
+    pub(crate) fn ensure_currency_enabled(&self, record: &InputRecord) -> Result<(), EngineError> {
+        if self.config.currencies || record.currency.is_unspecified() {
+            Ok(())
+        } else {
+            Err(EngineError::CurrenciesDisabled(record.tx_id))
+        }
+    }
+
+    /// Checks the fields of a transfer row.
+    pub(crate) fn validate_transfer(record: &InputRecord) -> Result<Transfer, EngineError> {
+        let amount = record.amount.ok_or(EngineError::MissingAmount(record.tx_id))?;
+        if amount <= Decimal::ZERO {
+            return Err(EngineError::AmountNotPositive(record.tx_id));
+        }
+        match record.counterparty {
+            Some(to) if to != record.client_id => Ok(Transfer {
+                tx_id: record.tx_id,
+                from: record.client_id,
+                to,
+                amount,
+                currency: record.currency,
+                timestamp: record.timestamp,
+            }),
+            _ => Err(EngineError::InvalidCounterparty(record.tx_id)),
+        }
+    }
+
+    /// Checks that a client's account in `currency` may be credited by a transfer. A missing
+    /// account is created by the credit.
+    pub(crate) fn ensure_receivable(&self, client_id: u16, currency: Currency) -> Result<(), EngineError> {
+        self.accounts
+            .get(&(client_id, currency))
+            .map_or(Ok(()), |account| account.ensure_active(client_id))
+    }
+
+    /// Applies the sending leg of a transfer, after every check that can still reject it.
+    /// The outcome of `ensure_receivable` is passed in, since the receiving account may live in
+    /// another shard.
+    pub(crate) fn debit_transfer(
+        &mut self,
+        transfer: &Transfer,
+        receivable: Result<(), EngineError>,
+    ) -> Result<(), EngineError> {
+        let Entry::Vacant(e) = self.transactions.entry(transfer.tx_id) else {
+            return Err(EngineError::DuplicateTransactionId(transfer.tx_id));
+        };
+        // Unlike a withdrawal, a transfer from a client without an account is an error: the
+        // receiving side would otherwise be credited with money that never existed.
+        let account = self
+            .accounts
+            .get_mut(&(transfer.from, transfer.currency))
+            .ok_or(EngineError::InsufficientFunds(transfer.from, transfer.amount))?;
+        account.ensure_active(transfer.from)?;
+        receivable?;
+        // The sender pays the fee.
+        let fee = self.config.fees.fee(TransactionType::Transfer, transfer.from, transfer.amount);
+        let credit_limit = self.config.credit_limits.limit(transfer.from);
+        account
+            .withdraw_on_credit(transfer.amount + fee, credit_limit)
+            .map_err(|_| EngineError::InsufficientFunds(transfer.from, transfer.amount))?;
+        Self::collect_fee(&mut self.fee_revenue, transfer.currency, fee);
+        Self::emit(&mut self.events, || Event::FundsTransferred {
+            tx: transfer.tx_id,
+            client: transfer.from,
+            counterparty: transfer.to,
+            currency: transfer.currency,
+            amount: transfer.amount,
+        });
+        Self::emit_fee(&mut self.events, transfer.tx_id, transfer.from, transfer.currency, fee);
+        e.insert(TransactionRecord {
+            counterparty: Some(transfer.to),
+            timestamp: transfer.timestamp,
+            ..TransactionRecord::new(transfer.from, transfer.amount, transfer.currency, TransactionDirection::Debit)
+        });
         Ok(())
     }
 
+    /// Applies the receiving leg of a transfer whose sending leg was accepted.
+    pub(crate) fn credit_transfer(&mut self, transfer: &Transfer) {
+        self.accounts
+            .entry((transfer.to, transfer.currency))
+            .or_default()
+            .deposit(transfer.amount);
+        self.transfer_credits.insert(
+            transfer.tx_id,
+            TransactionRecord {
+                counterparty: Some(transfer.from),
+                timestamp: transfer.timestamp,
+                ..TransactionRecord::new(transfer.to, transfer.amount, transfer.currency, TransactionDirection::Credit)
+            },
+        );
+    }
+
     fn handle_dispute(&mut self, record: InputRecord) -> Result<(), EngineError> {
         let tx_id = record.tx_id;
-        if let Some(tx) = self.transactions.get_mut(&tx_id) {
-            if tx.status == TransactionStatus::Disputed {
-                // Idempotent: if already disputed, do nothing.
-                return Ok(());
+        if let Some(tx) = Self::find_leg(&mut self.transactions, &mut self.transfer_credits, &record)? {
+            Self::ensure_currency(&record, tx)?;
+            let remaining = tx.disputable(self.config.redispute);
+            match tx.status {
+                TransactionStatus::Normal => {}
+                // Idempotent: repeating a dispute once nothing is left to dispute does nothing.
+                // Otherwise a further dispute, with an amount or without, holds a further portion.
+                TransactionStatus::Disputed if record.amount.is_none() && remaining.is_zero() => return Ok(()),
+                TransactionStatus::Disputed => {}
+                // Under `RedisputePolicy::Reject`, resolved amounts are final.
+                TransactionStatus::Resolved => {
+                    if remaining.is_zero() {
+                        return Err(EngineError::TransactionAlreadyResolved(tx_id));
+                    }
+                }
+                TransactionStatus::ChargedBack => {
+                    return Err(EngineError::TransactionChargedBack(tx_id))
+                }
             }
-            if let Some(account) = self.accounts.get_mut(&record.client_id) {
-                if account.locked {
-                    return Err(EngineError::AccountLocked(record.client_id));
+            Self::ensure_within_window(&self.config, &record, tx)?;
+            let amount = Self::portion(&record, remaining, EngineError::ExceedsDisputableAmount)?;
+            if let Some(account) = self.accounts.get_mut(&(tx.client_id, tx.currency)) {
+                Self::ensure_disputable(account, record.client_id)?;
+                let fee = self.config.fees.fee(record.transaction_type, record.client_id, amount);
+                // Disputing a credit takes its amount out of 'available', which may overdraw it.
+                let taken = match tx.direction {
+                    TransactionDirection::Credit => amount + fee,
+                    TransactionDirection::Debit => fee,
+                };
+                let over_limit = !taken.is_zero()
+                    && account.available - taken < -self.config.credit_limits.limit(tx.client_id);
+                if over_limit && self.config.over_limit == OverLimitPolicy::Reject {
+                    return Err(EngineError::ExceedsCreditLimit(tx_id, tx.client_id));
+                }
+                match tx.direction {
+                    TransactionDirection::Credit => account.hold_for_dispute(amount),
+                    TransactionDirection::Debit => account.hold_withdrawal_for_dispute(amount),
                 }
-                account.hold_for_dispute(tx.amount);
+                tx.disputed += amount;
                 tx.status = TransactionStatus::Disputed;
+                Self::charge_fee(account, &mut self.fee_revenue, tx.currency, fee);
+                let (client, currency) = (tx.client_id, tx.currency);
+                Self::emit(&mut self.events, || Event::FundsHeld { tx: tx_id, client, currency, amount });
+                Self::emit_fee(&mut self.events, tx_id, client, currency, fee);
+                if over_limit && self.config.over_limit == OverLimitPolicy::Lock {
+                    account.lock();
+                    Self::emit(&mut self.events, || Event::AccountLocked { tx: tx_id, client, currency });
+                }
                 Ok(())
             } else {
                 // If the client account doesn't exist, this is an invalid dispute.
@@ -122,16 +692,26 @@ impl PaymentEngine {
 
     fn handle_resolve(&mut self, record: InputRecord) -> Result<(), EngineError> {
         let tx_id = record.tx_id;
-        if let Some(tx) = self.transactions.get_mut(&tx_id) {
-            if tx.status != TransactionStatus::Disputed {
-                return Err(EngineError::TransactionNotDisputed(tx_id));
-            }
-            if let Some(account) = self.accounts.get_mut(&record.client_id) {
-                if account.locked {
-                    return Err(EngineError::AccountLocked(record.client_id));
+        if let Some(tx) = Self::find_leg(&mut self.transactions, &mut self.transfer_credits, &record)? {
+            Self::ensure_currency(&record, tx)?;
+            Self::ensure_disputed(tx_id, tx.status)?;
+            let amount = Self::portion(&record, tx.disputed, EngineError::ExceedsDisputedAmount)?;
+            if let Some(account) = self.accounts.get_mut(&(tx.client_id, tx.currency)) {
+                Self::ensure_disputable(account, record.client_id)?;
+                match tx.direction {
+                    TransactionDirection::Credit => account.release_from_dispute(amount),
+                    TransactionDirection::Debit => account.release_withdrawal_from_dispute(amount),
+                }
+                tx.disputed -= amount;
+                tx.resolved += amount;
+                if tx.disputed.is_zero() {
+                    tx.status = TransactionStatus::Resolved;
                 }
-                account.release_from_dispute(tx.amount);
-                tx.status = TransactionStatus::Normal;
+                let fee = self.config.fees.fee(record.transaction_type, record.client_id, amount);
+                Self::charge_fee(account, &mut self.fee_revenue, tx.currency, fee);
+                let (client, currency) = (tx.client_id, tx.currency);
+                Self::emit(&mut self.events, || Event::FundsReleased { tx: tx_id, client, currency, amount });
+                Self::emit_fee(&mut self.events, tx_id, client, currency, fee);
                 Ok(())
             } else {
                 // If the client account doesn't exist, this is an invalid resolve.
@@ -145,14 +725,35 @@ impl PaymentEngine {
 
     fn handle_chargeback(&mut self, record: InputRecord) -> Result<(), EngineError> {
         let tx_id = record.tx_id;
-        if let Some(tx) = self.transactions.get_mut(&tx_id) {
-            if tx.status != TransactionStatus::Disputed {
-                return Err(EngineError::TransactionNotDisputed(tx_id));
-            }
-            if let Some(account) = self.accounts.get_mut(&record.client_id) {
-                // A chargeback proceeds even if the account is locked.
+        if let Some(tx) = Self::find_leg(&mut self.transactions, &mut self.transfer_credits, &record)? {
+            Self::ensure_currency(&record, tx)?;
+            Self::ensure_disputed(tx_id, tx.status)?;
+            let amount = Self::portion(&record, tx.disputed, EngineError::ExceedsDisputedAmount)?;
+            if let Some(account) = self.accounts.get_mut(&(tx.client_id, tx.currency)) {
+                // A chargeback proceeds even if the account is locked or frozen.
                 // It finalizes the held funds removal and ensures the account is locked.
-                account.chargeback(tx.amount);
+                if account.status == AccountStatus::Closed {
+                    return Err(EngineError::AccountClosed(record.client_id));
+                }
+                let was_locked = account.locked;
+                match tx.direction {
+                    TransactionDirection::Credit => account.chargeback(amount),
+                    TransactionDirection::Debit => account.chargeback_withdrawal(amount),
+                }
+                tx.disputed -= amount;
+                tx.charged_back += amount;
+                // A partial chargeback leaves the rest of the dispute open.
+                if tx.disputed.is_zero() {
+                    tx.status = TransactionStatus::ChargedBack;
+                }
+                let fee = self.config.fees.fee(record.transaction_type, record.client_id, amount);
+                Self::charge_fee(account, &mut self.fee_revenue, tx.currency, fee);
+                let (client, currency) = (tx.client_id, tx.currency);
+                Self::emit(&mut self.events, || Event::ChargebackApplied { tx: tx_id, client, currency, amount });
+                Self::emit_fee(&mut self.events, tx_id, client, currency, fee);
+                if !was_locked {
+                    Self::emit(&mut self.events, || Event::AccountLocked { tx: tx_id, client, currency });
+                }
                 Ok(())
             } else {
                 // If the client account doesn't exist, this is an invalid chargeback.
@@ -163,13 +764,199 @@ impl PaymentEngine {
             Err(EngineError::TransactionNotFound(tx_id))
         }
     }
+
+    /// Applies an admin action to the client's balance in the row's currency or, without a
+    /// currency, to each of its balances that is not closed. Closed balances reject every action.
+    /// Returns the action for the audit log.
+    pub(crate) fn apply_admin(&mut self, record: &InputRecord) -> Result<AdminAction, EngineError> {
+        let reason = match &record.reason {
+            Some(reason) if !reason.is_empty() => reason.clone(),
+            _ => return Err(EngineError::MissingReason(record.tx_id)),
+        };
+        let mut targets: Vec<(Currency, &mut Account)> = self
+            .accounts
+            .iter_mut()
+            .filter(|((client_id, currency), _)| {
+                *client_id == record.client_id
+                    && (record.currency.is_unspecified() || *currency == record.currency)
+            })
+            .map(|((_, currency), account)| (*currency, account))
+            .collect();
+        if targets.is_empty() {
+            return Err(EngineError::AccountNotFound(record.client_id));
+        }
+        targets.retain(|(_, account)| account.status != AccountStatus::Closed);
+        if targets.is_empty() {
+            return Err(EngineError::AccountClosed(record.client_id));
+        }
+        // Sorted so the events come out in a deterministic order.
+        targets.sort_by_key(|(currency, _)| *currency);
+        let (tx, client) = (record.tx_id, record.client_id);
+        for (currency, account) in targets {
+            let reason = reason.clone();
+            match record.transaction_type {
+                TransactionType::Unlock => {
+                    account.unlock();
+                    Self::emit(&mut self.events, || Event::AccountUnlocked { tx, client, currency, reason });
+                }
+                TransactionType::Freeze => {
+                    account.freeze();
+                    Self::emit(&mut self.events, || Event::AccountFrozen { tx, client, currency, reason });
+                }
+                _ => {
+                    account.close();
+                    Self::emit(&mut self.events, || Event::AccountClosed { tx, client, currency, reason });
+                }
+            }
+        }
+        Ok(AdminAction {
+            tx: record.tx_id,
+            client: record.client_id,
+            currency: record.currency,
+            action: record.transaction_type,
+            reason,
+            timestamp: record.timestamp,
+        })
+    }
+
+    /// Queues an event of the record being applied, if events are being emitted at all.
+    fn emit(events: &mut Option<EventLog>, event: impl FnOnce() -> Event) {
+        if let Some(events) = events {
+            events.push(event());
+        }
+    }
+
+    /// Queues a `FeeCharged` event, unless no fee was charged.
+    fn emit_fee(events: &mut Option<EventLog>, tx: u32, client: u16, currency: Currency, fee: Decimal) {
+        if !fee.is_zero() {
+            Self::emit(events, || Event::FeeCharged { tx, client, currency, amount: fee });
+        }
+    }
+
+    /// Takes a fee from an account and adds it to the revenue in that currency.
+    fn charge_fee(account: &mut Account, revenue: &mut HashMap<Currency, Decimal>, currency: Currency, fee: Decimal) {
+        if !fee.is_zero() {
+            account.charge_fee(fee);
+            Self::collect_fee(revenue, currency, fee);
+        }
+    }
+
+    /// Adds a fee that was already taken from an account to the revenue in its currency.
+    fn collect_fee(revenue: &mut HashMap<Currency, Decimal>, currency: Currency, fee: Decimal) {
+        if !fee.is_zero() {
+            *revenue.entry(currency).or_default() += fee;
+        }
+    }
+
+    /// Checks that a dispute or resolve may act on the account: a frozen account still accepts
+    /// them, while a locked or closed one does not.
+    fn ensure_disputable(account: &Account, client_id: u16) -> Result<(), EngineError> {
+        match account.status {
+            AccountStatus::Closed => Err(EngineError::AccountClosed(client_id)),
+            _ if account.locked => Err(EngineError::AccountLocked(client_id)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Finds the ledger entry a dispute-family row refers to: the transaction itself or, for a
+    /// transfer, the leg that belongs to the row's client. Fails if the ID belongs to other clients.
+    fn find_leg<'a>(
+        transactions: &'a mut HashMap<u32, TransactionRecord>,
+        transfer_credits: &'a mut HashMap<u32, TransactionRecord>,
+        record: &InputRecord,
+    ) -> Result<Option<&'a mut TransactionRecord>, EngineError> {
+        let owner = |ledger: &HashMap<u32, TransactionRecord>| ledger.get(&record.tx_id).map(|tx| tx.client_id);
+        match (owner(transactions), owner(transfer_credits)) {
+            (None, None) => Ok(None),
+            (Some(client_id), _) if client_id == record.client_id => Ok(transactions.get_mut(&record.tx_id)),
+            (_, Some(client_id)) if client_id == record.client_id => Ok(transfer_credits.get_mut(&record.tx_id)),
+            _ => Err(EngineError::ClientMismatch(record.tx_id, record.client_id)),
+        }
+    }
+
+    /// Checks that a dispute-family row that gives a currency names the transaction's currency.
+    fn ensure_currency(record: &InputRecord, tx: &TransactionRecord) -> Result<(), EngineError> {
+        if !record.currency.is_unspecified() && record.currency != tx.currency {
+            return Err(EngineError::CurrencyMismatch(record.tx_id, record.currency));
+        }
+        Ok(())
+    }
+
+    /// Checks that a dispute comes within the configured window after its transaction. The check
+    /// is skipped unless both rows carry a timestamp, so time only ever comes from the input.
+    fn ensure_within_window(
+        config: &EngineConfig,
+        record: &InputRecord,
+        tx: &TransactionRecord,
+    ) -> Result<(), EngineError> {
+        if let (Some(window), Some(disputed_at), Some(created_at)) =
+            (config.dispute_window, record.timestamp, tx.timestamp)
+        {
+            if disputed_at.saturating_sub(created_at) > window.as_secs() {
+                return Err(EngineError::DisputeWindowExpired(record.tx_id));
+            }
+        }
+        Ok(())
+    }
+
+    /// The amount a dispute-family row acts on: the row's own amount if it gives one, otherwise
+    /// all of `limit`. A given amount must be positive and must not exceed `limit`.
+    fn portion(
+        record: &InputRecord,
+        limit: Decimal,
+        exceeds: fn(u32, Decimal) -> EngineError,
+    ) -> Result<Decimal, EngineError> {
+        match record.amount {
+            None => Ok(limit),
+            Some(amount) if amount <= Decimal::ZERO => Err(EngineError::AmountNotPositive(record.tx_id)),
+            Some(amount) if amount > limit => Err(exceeds(record.tx_id, amount)),
+            Some(amount) => Ok(amount),
+        }
+    }
+
+    /// Checks that a resolve or chargeback targets a transaction that is currently disputed.
+    fn ensure_disputed(tx_id: u32, status: TransactionStatus) -> Result<(), EngineError> {
+        match status {
+            TransactionStatus::Disputed => Ok(()),
+            TransactionStatus::Normal => Err(EngineError::TransactionNotDisputed(tx_id)),
+            TransactionStatus::Resolved => Err(EngineError::TransactionAlreadyResolved(tx_id)),
+            TransactionStatus::ChargedBack => Err(EngineError::TransactionChargedBack(tx_id)),
+        }
+    }
+}
+
+/// The state a record may change, as saved by `PaymentEngine::save`.
+pub(crate) struct Saved {
+    accounts: Vec<((u16, Currency), Option<Account>)>,
+    transaction: Option<TransactionRecord>,
+    transfer_credit: Option<TransactionRecord>,
+    tx_id: u32,
+    /// Length of the admin log.
+    admin_actions: usize,
+    fee_revenue: HashMap<Currency, Decimal>,
+    replays: u64,
+}
+
+/// A transfer row that passed validation.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Transfer {
+    pub(crate) tx_id: u32,
+    pub(crate) from: u16,
+    pub(crate) to: u16,
+    pub(crate) amount: Decimal,
+    pub(crate) currency: Currency,
+    pub(crate) timestamp: Option<u64>,
 }
 
 // --- Unit Tests ---
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{EngineConfig, RedisputePolicy};
     use crate::error::EngineError;
+    use crate::events::MemorySink;
+    use crate::fees::{Fee, FeeSchedule};
+    use crate::limits::CreditLimits;
     use rust_decimal_macros::dec;
 
     fn process_record(engine: &mut PaymentEngine, record: InputRecord) -> Result<(), EngineError> {
@@ -179,9 +966,9 @@ mod tests {
     #[test]
     fn test_deposit_and_withdrawal() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Withdrawal, client_id: 1, tx_id: 2, amount: Some(dec!(30.0)) }).unwrap();
-        let account = engine.accounts.get(&1).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(30.0))).unwrap();
+        let account = engine.account(1).unwrap();
         assert_eq!(account.available, dec!(70.0));
         assert_eq!(account.total(), dec!(70.0));
     }
@@ -189,23 +976,23 @@ mod tests {
     #[test]
     fn test_insufficient_funds() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(20.0)) }).unwrap();
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Withdrawal, client_id: 1, tx_id: 2, amount: Some(dec!(50.0)) });
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(20.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(50.0)));
         assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(50.0))));
-        let account = engine.accounts.get(&1).unwrap();
+        let account = engine.account(1).unwrap();
         assert_eq!(account.available, dec!(20.0));
     }
 
     #[test]
     fn test_full_dispute_resolve_cycle() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        let account_after_dispute = engine.accounts.get(&1).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        let account_after_dispute = engine.account(1).unwrap();
         assert_eq!(account_after_dispute.available, dec!(0));
         assert_eq!(account_after_dispute.held, dec!(100.0));
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Resolve, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        let account_after_resolve = engine.accounts.get(&1).unwrap();
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        let account_after_resolve = engine.account(1).unwrap();
         assert_eq!(account_after_resolve.available, dec!(100.0));
         assert_eq!(account_after_resolve.held, dec!(0));
     }
@@ -213,10 +1000,10 @@ mod tests {
     #[test]
     fn test_full_chargeback_cycle() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Chargeback, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        let account = engine.accounts.get(&1).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
         assert_eq!(account.total(), dec!(0));
         assert!(account.locked);
     }
@@ -224,50 +1011,50 @@ mod tests {
     #[test]
     fn test_tx_on_locked_account() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Chargeback, client_id: 1, tx_id: 1, amount: None }).unwrap();
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 2, amount: Some(dec!(50.0)) });
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let result = process_record(&mut engine, InputRecord::deposit(1, 2, dec!(50.0)));
         assert_eq!(result, Err(EngineError::AccountLocked(1)));
     }
 
     #[test]
     fn test_error_on_resolving_undisputed_tx() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Resolve, client_id: 1, tx_id: 1, amount: None });
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::resolve(1, 1));
         assert_eq!(result, Err(EngineError::TransactionNotDisputed(1)));
     }
 
     #[test]
     fn test_error_on_duplicate_transaction_id() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 2, tx_id: 1, amount: Some(dec!(50.0)) });
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::deposit(2, 1, dec!(50.0)));
         assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
     }
 
     #[test]
     fn test_dispute_non_existent_tx_is_ignored() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 99, amount: None });
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::dispute(1, 99));
         assert_eq!(result, Err(EngineError::TransactionNotFound(99)));
         // Ensure original account is unchanged
-        let account = engine.accounts.get(&1).unwrap();
+        let account = engine.account(1).unwrap();
         assert_eq!(account.available, dec!(100.0));
     }
 
     #[test]
     fn test_disputing_an_already_disputed_tx_is_idempotent() {
         let mut engine = PaymentEngine::new();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Deposit, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) }).unwrap();
-        process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 1, amount: None }).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
         // Second dispute should succeed with Ok(()) and not change state
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Dispute, client_id: 1, tx_id: 1, amount: None });
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1));
         assert!(result.is_ok());
         // Check that state is still the same (funds are held, not held twice)
-        let account = engine.accounts.get(&1).unwrap();
+        let account = engine.account(1).unwrap();
         assert_eq!(account.available, dec!(0));
         assert_eq!(account.held, dec!(100.0));
     }
@@ -276,9 +1063,713 @@ mod tests {
     fn test_withdrawal_from_non_existent_client_is_ignored() {
         let mut engine = PaymentEngine::new();
         // No deposits for client 1
-        let result = process_record(&mut engine, InputRecord { transaction_type: TransactionType::Withdrawal, client_id: 1, tx_id: 1, amount: Some(dec!(100.0)) });
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 1, dec!(100.0)));
         assert!(result.is_ok());
         // Ensure no account was created
-        assert!(engine.accounts.get(&1).is_none());
+        assert!(engine.account(1).is_none());
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_resolve_cycle() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(40.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 2)).unwrap();
+        let account_after_dispute = engine.account(1).unwrap();
+        assert_eq!(account_after_dispute.available, dec!(60.0));
+        assert_eq!(account_after_dispute.held, dec!(40.0));
+        process_record(&mut engine, InputRecord::resolve(1, 2)).unwrap();
+        let account_after_resolve = engine.account(1).unwrap();
+        assert_eq!(account_after_resolve.available, dec!(60.0));
+        assert_eq!(account_after_resolve.held, dec!(0));
+        assert!(!account_after_resolve.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_chargeback_restores_funds() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(40.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 2)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 2)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, dec!(100.0));
+        assert_eq!(account.held, dec!(0));
+        assert!(account.locked);
+    }
+
+    #[test]
+    fn test_error_on_duplicate_withdrawal_id() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 1, dec!(10.0)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+        assert_eq!(engine.account(1).unwrap().available, dec!(100.0));
+    }
+
+    #[test]
+    fn test_cross_client_dispute_is_rejected() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(2, 2, dec!(50.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::dispute(2, 1));
+        assert_eq!(result, Err(EngineError::ClientMismatch(1, 2)));
+        // Neither account may be touched
+        assert_eq!(engine.account(1).unwrap().available, dec!(100.0));
+        assert_eq!(engine.account(1).unwrap().held, dec!(0));
+        assert_eq!(engine.account(2).unwrap().available, dec!(50.0));
+        assert_eq!(engine.account(2).unwrap().held, dec!(0));
+    }
+
+    #[test]
+    fn test_cross_client_resolve_and_chargeback_are_rejected() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(2, 2, dec!(50.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        let resolve = process_record(&mut engine, InputRecord::resolve(2, 1));
+        assert_eq!(resolve, Err(EngineError::ClientMismatch(1, 2)));
+        let chargeback = process_record(&mut engine, InputRecord::chargeback(2, 1));
+        assert_eq!(chargeback, Err(EngineError::ClientMismatch(1, 2)));
+        let owner = engine.account(1).unwrap();
+        assert_eq!(owner.held, dec!(100.0));
+        assert!(!owner.locked);
+        let other = engine.account(2).unwrap();
+        assert_eq!(other.available, dec!(50.0));
+        assert!(!other.locked);
+    }
+
+    fn deposit_and_dispute(engine: &mut PaymentEngine) {
+        process_record(engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(engine, InputRecord::dispute(1, 1)).unwrap();
+    }
+
+    #[test]
+    fn test_chargeback_on_undisputed_tx_is_rejected() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::chargeback(1, 1));
+        assert_eq!(result, Err(EngineError::TransactionNotDisputed(1)));
+        assert!(!engine.account(1).unwrap().locked);
+    }
+
+    #[test]
+    fn test_second_chargeback_is_rejected() {
+        let mut engine = PaymentEngine::new();
+        deposit_and_dispute(&mut engine);
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let result = process_record(&mut engine, InputRecord::chargeback(1, 1));
+        assert_eq!(result, Err(EngineError::TransactionChargedBack(1)));
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.held, dec!(0));
+        assert_eq!(account.total(), dec!(0));
+    }
+
+    #[test]
+    fn test_charged_back_tx_cannot_be_disputed_or_resolved() {
+        let mut engine = PaymentEngine::new();
+        deposit_and_dispute(&mut engine);
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let dispute = process_record(&mut engine, InputRecord::dispute(1, 1));
+        assert_eq!(dispute, Err(EngineError::TransactionChargedBack(1)));
+        let resolve = process_record(&mut engine, InputRecord::resolve(1, 1));
+        assert_eq!(resolve, Err(EngineError::TransactionChargedBack(1)));
+        assert_eq!(engine.transactions.get(&1).unwrap().status, TransactionStatus::ChargedBack);
+    }
+
+    #[test]
+    fn test_resolved_tx_cannot_be_resolved_or_charged_back() {
+        let mut engine = PaymentEngine::new();
+        deposit_and_dispute(&mut engine);
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        let resolve = process_record(&mut engine, InputRecord::resolve(1, 1));
+        assert_eq!(resolve, Err(EngineError::TransactionAlreadyResolved(1)));
+        let chargeback = process_record(&mut engine, InputRecord::chargeback(1, 1));
+        assert_eq!(chargeback, Err(EngineError::TransactionAlreadyResolved(1)));
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, dec!(100.0));
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn test_redispute_of_resolved_tx_rejected_by_default() {
+        let mut engine = PaymentEngine::new();
+        deposit_and_dispute(&mut engine);
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1));
+        assert_eq!(result, Err(EngineError::TransactionAlreadyResolved(1)));
+        assert_eq!(engine.account(1).unwrap().held, dec!(0));
+    }
+
+    #[test]
+    fn test_redispute_of_resolved_tx_when_allowed() {
+        let mut engine = PaymentEngine::with_config(EngineConfig { redispute: RedisputePolicy::Allow, ..EngineConfig::default() });
+        deposit_and_dispute(&mut engine);
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available, dec!(0));
+        assert_eq!(account.held, dec!(100.0));
+        assert_eq!(engine.transactions.get(&1).unwrap().status, TransactionStatus::Disputed);
+    }
+
+    fn output_string(engine: &PaymentEngine, order: OutputOrder) -> String {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        engine.write_output_ordered(&mut wtr, order).unwrap();
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    fn sample_engine() -> PaymentEngine {
+        sample_engine_with(EngineConfig::default())
+    }
+
+    fn sample_engine_with(config: EngineConfig) -> PaymentEngine {
+        let mut engine = PaymentEngine::with_config(config);
+        process_record(&mut engine, InputRecord::deposit(3, 1, dec!(30.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 2, dec!(50.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(2, 3, dec!(5.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(3, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(3, 1)).unwrap();
+        engine
+    }
+
+    fn client_order(output: &str) -> Vec<&str> {
+        output.lines().skip(1).map(|line| &line[..1]).collect()
+    }
+
+    #[test]
+    fn test_output_sorted_by_client_id() {
+        let output = output_string(&sample_engine(), OutputOrder::ClientId);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n\
+             1,50.0000,0.0000,50.0000,false\n\
+             2,5.0000,0.0000,5.0000,false\n\
+             3,0.0000,0.0000,0.0000,true\n"
+        );
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        sample_engine().write_output(&mut wtr).unwrap();
+        assert_eq!(String::from_utf8(wtr.into_inner().unwrap()).unwrap(), output);
+    }
+
+    #[test]
+    fn test_output_sorted_by_total_and_locked() {
+        let engine = sample_engine();
+        assert_eq!(client_order(&output_string(&engine, OutputOrder::Total)), ["3", "2", "1"]);
+        assert_eq!(client_order(&output_string(&engine, OutputOrder::Locked)), ["3", "1", "2"]);
+    }
+
+    #[test]
+    fn test_read_accessors() {
+        let engine = sample_engine();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(50.0));
+        assert!(engine.account(3).unwrap().locked());
+        assert!(engine.account(9).is_none());
+        let mut clients: Vec<u16> = engine.accounts().map(|(client_id, _, _)| client_id).collect();
+        clients.sort();
+        assert_eq!(clients, [1, 2, 3]);
+        let tx = engine.transaction(1).unwrap();
+        assert_eq!(tx.client_id, 3);
+        assert_eq!(tx.status, TransactionStatus::ChargedBack);
+        assert!(engine.transaction(99).is_none());
+    }
+
+    #[test]
+    fn test_json_lines_output_keeps_four_decimals() {
+        let mut bytes = Vec::new();
+        sample_engine().write_output_json_lines(&mut bytes, OutputOrder::ClientId).unwrap();
+        let output = String::from_utf8(bytes).unwrap();
+        assert_eq!(
+            output.lines().next().unwrap(),
+            r#"{"client":1,"available":"50.0000","held":"0.0000","total":"50.0000","locked":false}"#
+        );
+        assert_eq!(output.lines().count(), 3);
+    }
+
+    fn with_currencies() -> EngineConfig {
+        EngineConfig { currencies: true, ..EngineConfig::default() }
+    }
+
+    #[test]
+    fn test_currencies_are_kept_in_separate_balances() {
+        let mut engine = PaymentEngine::with_config(with_currencies());
+        let (eur, usd): (Currency, Currency) = ("EUR".parse().unwrap(), "usd".parse().unwrap());
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0)).in_currency(eur)).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 2, dec!(3.0)).in_currency(usd)).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 3, dec!(5.0)).in_currency(usd));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(5.0))));
+        process_record(&mut engine, InputRecord::dispute(1, 2)).unwrap();
+        assert_eq!(engine.account_in(1, eur).unwrap().available(), dec!(10.0));
+        assert_eq!(engine.account_in(1, usd).unwrap().held(), dec!(3.0));
+        assert!(engine.account(1).is_none());
+    }
+
+    #[test]
+    fn test_dispute_in_other_currency_is_rejected() {
+        let mut engine = PaymentEngine::with_config(with_currencies());
+        let (eur, usd) = ("EUR".parse().unwrap(), "USD".parse().unwrap());
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0)).in_currency(eur)).unwrap();
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1).in_currency(usd));
+        assert_eq!(result, Err(EngineError::CurrencyMismatch(1, usd)));
+        assert_eq!(engine.transaction(1).unwrap().status, TransactionStatus::Normal);
+        process_record(&mut engine, InputRecord::dispute(1, 1).in_currency(eur)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        assert!(engine.account_in(1, eur).unwrap().locked());
+    }
+
+    #[test]
+    fn test_currency_needs_currencies_enabled() {
+        let mut engine = sample_engine();
+        let result = process_record(&mut engine, InputRecord::deposit(1, 4, dec!(2.5)).in_currency("EUR".parse().unwrap()));
+        assert_eq!(result, Err(EngineError::CurrenciesDisabled(4)));
+        assert!(output_string(&engine, OutputOrder::ClientId).starts_with("client,available,held,total,locked\n"));
+        // With currencies enabled, the column is there before any row names a currency.
+        let engine = sample_engine_with(with_currencies());
+        assert!(output_string(&engine, OutputOrder::ClientId).starts_with("client,currency,available,"));
+    }
+
+    #[test]
+    fn test_output_has_one_row_per_client_and_currency() {
+        let mut engine = sample_engine_with(with_currencies());
+        process_record(&mut engine, InputRecord::deposit(1, 4, dec!(2.5)).in_currency("EUR".parse().unwrap())).unwrap();
+        assert_eq!(
+            output_string(&engine, OutputOrder::ClientId),
+            "client,currency,available,held,total,locked\n\
+             1,,50.0000,0.0000,50.0000,false\n\
+             1,EUR,2.5000,0.0000,2.5000,false\n\
+             2,,5.0000,0.0000,5.0000,false\n\
+             3,,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_between_clients() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::transfer(1, 2, 2, dec!(4.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(6.0));
+        assert_eq!(engine.account(2).unwrap().available(), dec!(4.0));
+        assert_eq!(engine.transaction(2).unwrap().counterparty, Some(2));
+        assert_eq!(engine.transfer_credit(2).unwrap().client_id, 2);
+    }
+
+    #[test]
+    fn test_rejected_transfer_changes_neither_account() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(2, 2, dec!(1.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(2, 2)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(2, 2)).unwrap();
+
+        let result = process_record(&mut engine, InputRecord::transfer(1, 3, 2, dec!(4.0)));
+        assert_eq!(result, Err(EngineError::AccountLocked(2)));
+        let result = process_record(&mut engine, InputRecord::transfer(1, 3, 3, dec!(40.0)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(40.0))));
+        let result = process_record(&mut engine, InputRecord::transfer(1, 3, 1, dec!(4.0)));
+        assert_eq!(result, Err(EngineError::InvalidCounterparty(3)));
+        let result = process_record(&mut engine, InputRecord::transfer(4, 3, 1, dec!(4.0)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(4, dec!(4.0))));
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+        assert!(engine.account(3).is_none());
+        assert!(engine.transaction(3).is_none());
+    }
+
+    #[test]
+    fn test_each_transfer_leg_is_disputed_by_its_own_client() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::transfer(1, 2, 2, dec!(4.0))).unwrap();
+
+        // The sender disputes its debit: the amount is re-credited into held.
+        process_record(&mut engine, InputRecord::dispute(1, 2)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec!(4.0));
+        process_record(&mut engine, InputRecord::resolve(1, 2)).unwrap();
+
+        // The receiver disputes its credit: the amount is held, and a chargeback removes it.
+        process_record(&mut engine, InputRecord::dispute(2, 2)).unwrap();
+        assert_eq!(engine.account(2).unwrap().held(), dec!(4.0));
+        process_record(&mut engine, InputRecord::chargeback(2, 2)).unwrap();
+        let receiver = engine.account(2).unwrap();
+        assert_eq!(receiver.total(), dec!(0));
+        assert!(receiver.locked());
+        assert_eq!(engine.transaction(2).unwrap().status, TransactionStatus::Resolved);
+        assert_eq!(engine.transfer_credit(2).unwrap().status, TransactionStatus::ChargedBack);
+
+        let result = process_record(&mut engine, InputRecord::dispute(3, 2));
+        assert_eq!(result, Err(EngineError::ClientMismatch(2, 3)));
+        let result = process_record(&mut engine, InputRecord::deposit(2, 2, dec!(1.0)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(2)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_partial_dispute_holds_only_its_amount() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(30.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(50.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (dec!(20.0), dec!(80.0)));
+
+        let result = process_record(&mut engine, InputRecord { amount: Some(dec!(25.0)), ..InputRecord::dispute(1, 1) });
+        assert_eq!(result, Err(EngineError::ExceedsDisputableAmount(1, dec!(25.0))));
+        let result = process_record(&mut engine, InputRecord { amount: Some(dec!(90.0)), ..InputRecord::resolve(1, 1) });
+        assert_eq!(result, Err(EngineError::ExceedsDisputedAmount(1, dec!(90.0))));
+
+        process_record(&mut engine, InputRecord { amount: Some(dec!(50.0)), ..InputRecord::resolve(1, 1) }).unwrap();
+        let tx = engine.transaction(1).unwrap();
+        assert_eq!(tx.status, TransactionStatus::Disputed);
+        assert_eq!(tx.disputed, dec!(30.0));
+        assert_eq!(tx.disputable(RedisputePolicy::Reject), dec!(20.0));
+        assert_eq!(tx.disputable(RedisputePolicy::Allow), dec!(70.0));
+    }
+
+    #[test]
+    fn test_dispute_without_amount_holds_the_rest_of_a_partly_disputed_tx() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(30.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (dec!(0.0), dec!(100.0)));
+        assert_eq!(engine.transaction(1).unwrap().disputed, dec!(100.0));
+
+        // Once all of it is disputed, a repeated dispute is a no-op.
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec!(100.0));
+    }
+
+    #[test]
+    fn test_partial_chargeback_keeps_rest_of_dispute_open() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(40.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(15.0)), ..InputRecord::chargeback(1, 1) }).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available(), account.held(), account.total()), (dec!(60.0), dec!(25.0), dec!(85.0)));
+        assert!(account.locked());
+        assert_eq!(engine.transaction(1).unwrap().status, TransactionStatus::Disputed);
+
+        // Without an amount, the chargeback takes whatever is still under dispute.
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (dec!(60.0), dec!(0)));
+        let tx = engine.transaction(1).unwrap();
+        assert_eq!(tx.status, TransactionStatus::ChargedBack);
+        assert_eq!(tx.charged_back, dec!(40.0));
+    }
+
+    #[test]
+    fn test_undisputed_remainder_of_resolved_tx_can_be_disputed() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord { amount: Some(dec!(40.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec!(60.0));
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1));
+        assert_eq!(result, Err(EngineError::TransactionAlreadyResolved(1)));
+    }
+
+    #[test]
+    fn test_dispute_outside_window_is_rejected() {
+        let day = 24 * 60 * 60;
+        let config = EngineConfig { dispute_window: Some(std::time::Duration::from_secs(120 * day)), ..EngineConfig::default() };
+        let mut engine = PaymentEngine::with_config(config);
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0)).at(day)).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 2, dec!(10.0)).at(day)).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 3, dec!(10.0))).unwrap();
+
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1).at(122 * day));
+        assert_eq!(result, Err(EngineError::DisputeWindowExpired(1)));
+        assert_eq!(engine.account(1).unwrap().held(), dec!(0));
+        process_record(&mut engine, InputRecord::dispute(1, 2).at(121 * day)).unwrap();
+        // Without both timestamps there is nothing to measure, so the dispute goes ahead.
+        process_record(&mut engine, InputRecord::dispute(1, 3).at(500 * day)).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec!(30.0));
+    }
+
+    fn admin(action: TransactionType, client_id: u16, tx_id: u32) -> InputRecord {
+        InputRecord::admin(action, client_id, tx_id, "risk-review")
+    }
+
+    #[test]
+    fn test_unlock_clears_chargeback_lock() {
+        let mut engine = sample_engine();
+        process_record(&mut engine, admin(TransactionType::Unlock, 3, 100)).unwrap();
+        assert!(!engine.account(3).unwrap().locked());
+        process_record(&mut engine, InputRecord::deposit(3, 4, dec!(1.0))).unwrap();
+        assert_eq!(engine.admin_actions().len(), 1);
+        assert_eq!(engine.admin_actions()[0].to_string(), "Unlock of client 3 (tx 100, reason: risk-review)");
+    }
+
+    #[test]
+    fn test_frozen_account_only_accepts_dispute_family() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, admin(TransactionType::Freeze, 1, 100)).unwrap();
+        assert_eq!(process_record(&mut engine, InputRecord::deposit(1, 2, dec!(1.0))), Err(EngineError::AccountFrozen(1)));
+        assert_eq!(process_record(&mut engine, InputRecord::withdrawal(1, 3, dec!(1.0))), Err(EngineError::AccountFrozen(1)));
+        process_record(&mut engine, InputRecord::deposit(2, 4, dec!(1.0))).unwrap();
+        assert_eq!(process_record(&mut engine, InputRecord::transfer(2, 5, 1, dec!(1.0))), Err(EngineError::AccountFrozen(1)));
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.total(), account.status()), (dec!(0), AccountStatus::Frozen));
+
+        // Unlocking clears both the freeze and the chargeback lock.
+        process_record(&mut engine, admin(TransactionType::Unlock, 1, 101)).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 6, dec!(1.0))).unwrap();
+    }
+
+    #[test]
+    fn test_closed_account_rejects_everything() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 2, dec!(5.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 2)).unwrap();
+        process_record(&mut engine, admin(TransactionType::Close, 1, 100)).unwrap();
+        let closed = Err(EngineError::AccountClosed(1));
+        assert_eq!(process_record(&mut engine, InputRecord::deposit(1, 3, dec!(1.0))), closed);
+        assert_eq!(process_record(&mut engine, InputRecord::dispute(1, 1)), closed);
+        assert_eq!(process_record(&mut engine, InputRecord::resolve(1, 2)), closed);
+        assert_eq!(process_record(&mut engine, InputRecord::chargeback(1, 2)), closed);
+        assert_eq!(process_record(&mut engine, admin(TransactionType::Unlock, 1, 101)), closed);
+        assert_eq!(engine.account(1).unwrap().total(), dec!(15.0));
+        assert_eq!(engine.admin_actions().len(), 1);
+    }
+
+    #[test]
+    fn test_admin_action_needs_reason_and_account() {
+        let mut engine = PaymentEngine::with_config(with_currencies());
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::new(TransactionType::Freeze, 1, 100, None));
+        assert_eq!(result, Err(EngineError::MissingReason(100)));
+        let result = process_record(&mut engine, admin(TransactionType::Freeze, 2, 100));
+        assert_eq!(result, Err(EngineError::AccountNotFound(2)));
+        let result = process_record(&mut engine, admin(TransactionType::Freeze, 1, 100).in_currency("EUR".parse().unwrap()));
+        assert_eq!(result, Err(EngineError::AccountNotFound(1)));
+        assert!(engine.admin_actions().is_empty());
+    }
+
+    #[test]
+    fn test_output_shows_status_with_status_column() {
+        let mut engine = sample_engine();
+        process_record(&mut engine, admin(TransactionType::Freeze, 2, 100)).unwrap();
+        assert!(output_string(&engine, OutputOrder::ClientId).starts_with("client,available,held,total,locked\n"));
+        let mut engine = sample_engine_with(EngineConfig { status_column: true, ..EngineConfig::default() });
+        process_record(&mut engine, admin(TransactionType::Freeze, 2, 100)).unwrap();
+        assert_eq!(
+            output_string(&engine, OutputOrder::ClientId),
+            "client,available,held,total,locked,status\n\
+             1,50.0000,0.0000,50.0000,false,active\n\
+             2,5.0000,0.0000,5.0000,false,frozen\n\
+             3,0.0000,0.0000,0.0000,true,active\n"
+        );
+    }
+
+    fn engine_with_fees() -> PaymentEngine {
+        let mut fees = FeeSchedule::default();
+        fees.set_default(TransactionType::Withdrawal, Fee { percent: dec!(1.5), flat: dec!(0.25) });
+        fees.set_default(TransactionType::Chargeback, Fee { percent: dec!(0), flat: dec!(15) });
+        fees.set_override(2, TransactionType::Withdrawal, Fee::default());
+        PaymentEngine::with_config(EngineConfig { fees, ..EngineConfig::default() })
+    }
+
+    #[test]
+    fn test_withdrawal_fee_is_charged_and_collected() {
+        let mut engine = engine_with_fees();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(50.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(49.0));
+        // The ledger keeps the withdrawn amount; the fee is not disputable.
+        assert_eq!(engine.transaction(2).unwrap().amount, dec!(50.0));
+        assert_eq!(engine.fee_revenue(), vec![(Currency::default(), dec!(1.0))]);
+    }
+
+    #[test]
+    fn test_withdrawal_must_cover_its_fee() {
+        let mut engine = engine_with_fees();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(10.0)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(10.0))));
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+        assert!(engine.fee_revenue().is_empty());
+    }
+
+    #[test]
+    fn test_deposit_must_cover_its_fee() {
+        let mut fees = FeeSchedule::default();
+        fees.set_default(TransactionType::Deposit, Fee { percent: dec!(0), flat: dec!(2) });
+        let mut engine = PaymentEngine::with_config(EngineConfig { fees, ..EngineConfig::default() });
+        let result = process_record(&mut engine, InputRecord::deposit(1, 1, dec!(1.5)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(1.5))));
+        assert!(engine.account(1).is_none());
+        process_record(&mut engine, InputRecord::deposit(1, 2, dec!(5.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(3.0));
+        assert_eq!(engine.fee_revenue(), vec![(Currency::default(), dec!(2))]);
+    }
+
+    #[test]
+    fn test_client_override_replaces_default_fee() {
+        let mut engine = engine_with_fees();
+        process_record(&mut engine, InputRecord::deposit(2, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(2, 2, dec!(10.0))).unwrap();
+        assert_eq!(engine.account(2).unwrap().total(), dec!(0));
+        assert!(engine.fee_revenue().is_empty());
+    }
+
+    #[test]
+    fn test_chargeback_fee_may_leave_balance_negative() {
+        let mut engine = engine_with_fees();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec!(-15));
+        assert!(account.locked());
+        assert_eq!(engine.fee_revenue(), vec![(Currency::default(), dec!(15))]);
+    }
+
+    fn engine_with_credit(over_limit: OverLimitPolicy) -> PaymentEngine {
+        let mut credit_limits = CreditLimits::default();
+        credit_limits.set(1, dec!(100.0));
+        PaymentEngine::with_config(EngineConfig { credit_limits, over_limit, ..EngineConfig::default() })
+    }
+
+    #[test]
+    fn test_withdrawal_may_use_credit_limit() {
+        let mut engine = engine_with_credit(OverLimitPolicy::Allow);
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(50.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(150.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 3, dec!(0.01)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(1, dec!(0.01))));
+        let account = engine.account(1).unwrap();
+        assert_eq!(account.available(), dec!(-100.0));
+        assert_eq!(account.credit_used(), dec!(100.0));
+
+        process_record(&mut engine, InputRecord::deposit(2, 4, dec!(5.0))).unwrap();
+        let result = process_record(&mut engine, InputRecord::withdrawal(2, 5, dec!(6.0)));
+        assert_eq!(result, Err(EngineError::InsufficientFunds(2, dec!(6.0))));
+        assert_eq!(
+            output_string(&engine, OutputOrder::ClientId),
+            "client,available,held,total,credit_used,locked\n\
+             1,-100.0000,0.0000,-100.0000,100.0000,false\n\
+             2,5.0000,0.0000,5.0000,0.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_dispute_past_credit_limit_follows_policy() {
+        let setup = |policy| {
+            let mut engine = engine_with_credit(policy);
+            process_record(&mut engine, InputRecord::deposit(1, 1, dec!(50.0))).unwrap();
+            process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(120.0))).unwrap();
+            engine
+        };
+
+        let mut engine = setup(OverLimitPolicy::Allow);
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(-120.0));
+        assert!(!engine.account(1).unwrap().locked());
+
+        let mut engine = setup(OverLimitPolicy::Reject);
+        let result = process_record(&mut engine, InputRecord::dispute(1, 1));
+        assert_eq!(result, Err(EngineError::ExceedsCreditLimit(1, 1)));
+        assert_eq!(engine.account(1).unwrap().held(), dec!(0));
+        // A dispute that stays within the limit is still accepted.
+        process_record(&mut engine, InputRecord { amount: Some(dec!(30.0)), ..InputRecord::dispute(1, 1) }).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(-100.0));
+
+        let mut engine = setup(OverLimitPolicy::Lock);
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().held(), dec!(50.0));
+        assert!(engine.account(1).unwrap().locked());
+    }
+
+    #[test]
+    fn test_dispute_locked_past_credit_limit_is_resolved_after_an_unlock() {
+        let mut engine = engine_with_credit(OverLimitPolicy::Lock);
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(50.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(120.0))).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        let result = process_record(&mut engine, InputRecord::resolve(1, 1));
+        assert_eq!(result, Err(EngineError::AccountLocked(1)));
+
+        process_record(&mut engine, InputRecord::admin(TransactionType::Unlock, 1, 3, "limit-review")).unwrap();
+        process_record(&mut engine, InputRecord::resolve(1, 1)).unwrap();
+        let account = engine.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (dec!(-70.0), dec!(0)));
+        assert!(!account.locked());
+    }
+
+    #[test]
+    fn test_replayed_row_is_a_no_op() {
+        let mut engine = PaymentEngine::new();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(4.0))).unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.00))).unwrap();
+        process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(4.0))).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(6.0));
+        assert_eq!(engine.replays(), 2);
+
+        // Reusing an ID for a different row is still rejected.
+        let result = process_record(&mut engine, InputRecord::deposit(1, 1, dec!(11.0)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+        let result = process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0)).at(5));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+        let result = process_record(&mut engine, InputRecord::withdrawal(1, 1, dec!(10.0)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+        assert_eq!(engine.replays(), 2);
+    }
+
+    fn engine_with_events() -> (PaymentEngine, MemorySink) {
+        let sink = MemorySink::default();
+        let mut engine = engine_with_fees();
+        engine.set_event_sink(Box::new(sink.clone()));
+        (engine, sink)
+    }
+
+    #[test]
+    fn test_events_follow_the_dispute_lifecycle() {
+        let (mut engine, sink) = engine_with_events();
+        engine.config.currencies = true;
+        let eur: Currency = "EUR".parse().unwrap();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(100.0)).in_currency(eur)).unwrap();
+        process_record(&mut engine, InputRecord::dispute(1, 1)).unwrap();
+        process_record(&mut engine, InputRecord::chargeback(1, 1)).unwrap();
+        assert_eq!(
+            sink.events(),
+            vec![
+                Event::FundsDeposited { tx: 1, client: 1, currency: eur, amount: dec!(100.0) },
+                Event::FundsHeld { tx: 1, client: 1, currency: eur, amount: dec!(100.0) },
+                Event::ChargebackApplied { tx: 1, client: 1, currency: eur, amount: dec!(100.0) },
+                Event::FeeCharged { tx: 1, client: 1, currency: eur, amount: dec!(15) },
+                Event::AccountLocked { tx: 1, client: 1, currency: eur },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejected_record_emits_only_its_rejection() {
+        let (mut engine, sink) = engine_with_events();
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        // The withdrawal fails on its fee, after the amount alone would have been covered.
+        assert!(process_record(&mut engine, InputRecord::withdrawal(1, 2, dec!(10.0))).is_err());
+        // A replay changes nothing, so it emits nothing either.
+        process_record(&mut engine, InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        assert_eq!(
+            sink.events(),
+            vec![
+                Event::FundsDeposited { tx: 1, client: 1, currency: Currency::default(), amount: dec!(10.0) },
+                Event::TransactionRejected {
+                    tx: 2,
+                    client: 1,
+                    error: "InsufficientFunds".to_string(),
+                    message: "Insufficient funds for client 1 to withdraw 10.0".to_string(),
+                },
+            ]
+        );
+    }
+}
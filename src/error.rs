@@ -9,6 +9,31 @@ pub enum AppError {
     Csv(#[from] csv::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Wal(#[from] WalError),
+    #[error(transparent)]
+    History(#[from] HistoryError),
+    #[error(transparent)]
+    Reconcile(#[from] ReconcileError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("Invariant violated: {0}")]
+    Invariant(#[from] InvariantViolation),
+    #[error("Audit found {0} invariant violations")]
+    AuditFailed(usize),
+}
+
+/// Defines the errors that can occur while saving or loading engine snapshots.
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("Unsupported snapshot version {0}")]
+    UnsupportedVersion(u64),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 /// Defines all possible logical errors within the payment engine.
@@ -16,10 +41,18 @@ pub enum AppError {
 pub enum EngineError {
     #[error("Account {0} is locked")]
     AccountLocked(u16),
-    #[error("Transaction {0} not found or is not a disputable deposit")]
+    #[error("Transaction {0} not found or is not a disputable deposit or withdrawal")]
     TransactionNotFound(u32),
+    #[error("Transaction {0} does not belong to client {1}")]
+    ClientMismatch(u32, u16),
+    #[error("Transaction {0} is not in currency {1}")]
+    CurrencyMismatch(u32, crate::models::Currency),
     #[error("Transaction {0} is not currently under dispute")]
     TransactionNotDisputed(u32),
+    #[error("Transaction {0} has already been resolved")]
+    TransactionAlreadyResolved(u32),
+    #[error("Transaction {0} has already been charged back")]
+    TransactionChargedBack(u32),
     #[error("Insufficient funds for client {0} to withdraw {1}")]
     InsufficientFunds(u16, rust_decimal::Decimal),
     #[error("Duplicate transaction ID {0}. Transaction ignored.")]
@@ -28,4 +61,146 @@ pub enum EngineError {
     AmountNotPositive(u32),
     #[error("Deposit or withdrawal for tx {0} is missing an amount")]
     MissingAmount(u32),
-}
\ No newline at end of file
+    #[error("Transfer {0} needs a counterparty other than the sending client")]
+    InvalidCounterparty(u32),
+    #[error("Cannot dispute {1} of transaction {0}: it exceeds the amount left to dispute")]
+    ExceedsDisputableAmount(u32, rust_decimal::Decimal),
+    #[error("Cannot settle {1} of transaction {0}: it exceeds the amount under dispute")]
+    ExceedsDisputedAmount(u32, rust_decimal::Decimal),
+    #[error("Transaction {0} is too old to be disputed")]
+    DisputeWindowExpired(u32),
+    #[error("Account {0} is frozen")]
+    AccountFrozen(u16),
+    #[error("Account {0} is closed")]
+    AccountClosed(u16),
+    #[error("Client {0} has no account to apply the admin action to")]
+    AccountNotFound(u16),
+    #[error("Admin action {0} is missing a reason code")]
+    MissingReason(u32),
+    #[error("Dispute of transaction {0} would take client {1} past their credit limit")]
+    ExceedsCreditLimit(u32, u16),
+    #[error("Transaction {0} names a currency, but currencies are not enabled")]
+    CurrenciesDisabled(u32),
+    /// The record was applied but left the state inconsistent; see `set_invariant_checks`.
+    #[error("Invariant violated: {0}")]
+    InvariantViolated(InvariantViolation),
+}
+
+impl EngineError {
+    /// The variant name, as a stable machine-readable error code.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            EngineError::AccountLocked(_) => "AccountLocked",
+            EngineError::TransactionNotFound(_) => "TransactionNotFound",
+            EngineError::ClientMismatch(_, _) => "ClientMismatch",
+            EngineError::CurrencyMismatch(_, _) => "CurrencyMismatch",
+            EngineError::TransactionNotDisputed(_) => "TransactionNotDisputed",
+            EngineError::TransactionAlreadyResolved(_) => "TransactionAlreadyResolved",
+            EngineError::TransactionChargedBack(_) => "TransactionChargedBack",
+            EngineError::InsufficientFunds(_, _) => "InsufficientFunds",
+            EngineError::DuplicateTransactionId(_) => "DuplicateTransactionId",
+            EngineError::AmountNotPositive(_) => "AmountNotPositive",
+            EngineError::MissingAmount(_) => "MissingAmount",
+            EngineError::InvalidCounterparty(_) => "InvalidCounterparty",
+            EngineError::ExceedsDisputableAmount(_, _) => "ExceedsDisputableAmount",
+            EngineError::ExceedsDisputedAmount(_, _) => "ExceedsDisputedAmount",
+            EngineError::DisputeWindowExpired(_) => "DisputeWindowExpired",
+            EngineError::AccountFrozen(_) => "AccountFrozen",
+            EngineError::AccountClosed(_) => "AccountClosed",
+            EngineError::AccountNotFound(_) => "AccountNotFound",
+            EngineError::MissingReason(_) => "MissingReason",
+            EngineError::ExceedsCreditLimit(_, _) => "ExceedsCreditLimit",
+            EngineError::CurrenciesDisabled(_) => "CurrenciesDisabled",
+            EngineError::InvariantViolated(_) => "InvariantViolated",
+        }
+    }
+}
+
+/// Defines the consistency rules an engine state can break. Currencies are printed after the
+/// amounts, and the unspecified currency not at all.
+#[derive(Debug, Clone, Error, PartialEq)]
+pub enum InvariantViolation {
+    #[error("Client {0} has negative held funds of {2}{}", in_currency(.1))]
+    NegativeHeld(u16, crate::models::Currency, rust_decimal::Decimal),
+    #[error("Client {0} holds {2}{c} but has {3}{c} under dispute", c = in_currency(.1))]
+    HeldMismatch(u16, crate::models::Currency, rust_decimal::Decimal, rust_decimal::Decimal),
+    #[error(
+        "Balances add up to {1}{c} but deposits less withdrawals, chargebacks and fees add up to {2}{c}",
+        c = in_currency(.0)
+    )]
+    FundsNotConserved(crate::models::Currency, rust_decimal::Decimal, rust_decimal::Decimal),
+}
+
+fn in_currency(currency: &crate::models::Currency) -> String {
+    if currency.is_unspecified() {
+        String::new()
+    } else {
+        format!(" {}", currency)
+    }
+}
+
+/// Defines the errors that can occur while writing or recovering the write-ahead log.
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+    #[error("Write-ahead log is corrupt at byte offset {0}")]
+    Corrupt(u64),
+    #[error("Write-ahead log entry {0} was rejected on replay: {1}")]
+    Replay(u64, EngineError),
+    #[error("Input row {0} was applied but could not be logged; checkpoint before processing more records")]
+    Unlogged(u64),
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Defines the errors that can occur while replaying input for a point-in-time query.
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("Input ends before row {0}")]
+    RowNotReached(u64),
+    #[error("No row in the input carries transaction {0}")]
+    TxNotReached(u32),
+    #[error("Client {0} had no account as of input row {1}")]
+    AccountNotFound(u16, u64),
+    #[error("The checkpoint interval must be at least one row")]
+    ZeroInterval,
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Defines the errors that can occur while reading a fee schedule or credit limit file.
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Line {0}: {1} must not be negative")]
+    Negative(u64, &'static str),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Defines the errors that can occur while reading an expected-accounts file.
+#[derive(Debug, Error)]
+pub enum ReconcileError {
+    #[error("Client {0} is listed twice in currency '{1}'")]
+    DuplicateAccount(u16, crate::models::Currency),
+    #[error("Cannot map unknown field '{0}'")]
+    UnknownField(String),
+    #[error("Expected a JSON object per line, found: {0}")]
+    NotAnObject(String),
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,233 @@
+use crate::models::Currency;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// One effect of an applied record, or the rejection of a record.
+///
+/// A record's events are emitted together once it was applied, in the order the effects
+/// happened, so a consumer sees exactly what the engine did. `currency` is the balance that
+/// changed; it is left out for the unspecified currency.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    FundsDeposited {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    FundsWithdrawn {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    /// Both legs of a transfer.
+    FundsTransferred {
+        tx: u32,
+        client: u16,
+        counterparty: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    FeeCharged {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    /// A dispute moved funds into 'held'.
+    FundsHeld {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    /// A resolve released held funds.
+    FundsReleased {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    ChargebackApplied {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        amount: Decimal,
+    },
+    /// A balance became locked, by a chargeback or a dispute past the credit limit.
+    AccountLocked {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+    },
+    AccountUnlocked {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        reason: String,
+    },
+    AccountFrozen {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        reason: String,
+    },
+    AccountClosed {
+        tx: u32,
+        client: u16,
+        #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+        currency: Currency,
+        reason: String,
+    },
+    /// A record that changed nothing, with the `EngineError` variant name and message.
+    TransactionRejected {
+        tx: u32,
+        client: u16,
+        error: String,
+        message: String,
+    },
+}
+
+/// Where the engine sends its events.
+pub trait EventSink: Send {
+    fn emit(&mut self, event: &Event);
+
+    /// Flushes buffered events, reporting any write that failed since the last flush.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps events in memory. Clones share the same events, so a clone can be handed to the
+/// engine while the original is used to read them.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl MemorySink {
+    /// The events emitted so far, oldest first.
+    pub fn events(&self) -> Vec<Event> {
+        self.events.lock().expect("event lock poisoned").clone()
+    }
+
+    /// Removes and returns the events emitted so far, oldest first.
+    pub fn take_events(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock().expect("event lock poisoned"))
+    }
+}
+
+impl EventSink for MemorySink {
+    fn emit(&mut self, event: &Event) {
+        self.events.lock().expect("event lock poisoned").push(event.clone());
+    }
+}
+
+/// Writes events as JSON Lines, one object per event tagged with its `event` name.
+pub struct JsonLinesSink<W: Write + Send> {
+    writer: W,
+    /// The first failed write, reported by the next `flush`.
+    error: Option<io::Error>,
+}
+
+impl<W: Write + Send> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer, error: None }
+    }
+}
+
+impl<W: Write + Send> EventSink for JsonLinesSink<W> {
+    fn emit(&mut self, event: &Event) {
+        if self.error.is_some() {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.writer, event)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            self.error = Some(e);
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+/// A sink together with the events of the record being applied, which are only emitted once
+/// the record is accepted.
+pub(crate) struct EventLog {
+    sink: Box<dyn EventSink>,
+    pending: Vec<Event>,
+}
+
+impl EventLog {
+    pub(crate) fn new(sink: Box<dyn EventSink>) -> Self {
+        Self {
+            sink,
+            pending: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.pending.push(event);
+    }
+
+    /// Emits the pending events of an accepted record.
+    pub(crate) fn commit(&mut self) {
+        for event in self.pending.drain(..) {
+            self.sink.emit(&event);
+        }
+    }
+
+    /// Drops the pending events of a rejected record and emits the rejection instead.
+    pub(crate) fn reject(&mut self, event: Event) {
+        self.pending.clear();
+        self.sink.emit(&event);
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+
+    pub(crate) fn into_sink(self) -> Box<dyn EventSink> {
+        self.sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_json_lines_sink_tags_events() {
+        let mut buffer = Vec::new();
+        let mut sink = JsonLinesSink::new(&mut buffer);
+        sink.emit(&Event::FundsDeposited { tx: 1, client: 2, currency: Currency::default(), amount: dec!(1.5) });
+        sink.emit(&Event::AccountLocked { tx: 3, client: 2, currency: "EUR".parse().unwrap() });
+        sink.flush().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer).unwrap(),
+            "{\"event\":\"FundsDeposited\",\"tx\":1,\"client\":2,\"amount\":\"1.5\"}\n\
+             {\"event\":\"AccountLocked\",\"tx\":3,\"client\":2,\"currency\":\"EUR\"}\n"
+        );
+    }
+}
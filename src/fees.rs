@@ -0,0 +1,128 @@
+use crate::error::ConfigError;
+use crate::models::TransactionType;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// The fee charged for one transaction type: a percentage of the row's amount plus a flat fee.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Fee {
+    /// Percentage of the amount, e.g. `1.5` for 1.5%.
+    pub percent: Decimal,
+    pub flat: Decimal,
+}
+
+impl Fee {
+    /// The fee on `amount`, rounded to four decimal places like every reported amount.
+    pub fn on(&self, amount: Decimal) -> Decimal {
+        (amount * self.percent / Decimal::ONE_HUNDRED + self.flat).round_dp(4)
+    }
+}
+
+/// Fees per transaction type, with optional per-client overrides.
+///
+/// A client override replaces the default fee for that type entirely, so an override of zero
+/// waives the fee. Types without a fee are free.
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedule {
+    defaults: HashMap<TransactionType, Fee>,
+    overrides: HashMap<(u16, TransactionType), Fee>,
+}
+
+/// One row of a fee schedule file. An empty `client` sets the default for the type.
+#[derive(Debug, Deserialize)]
+struct FeeRow {
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: Option<u16>,
+    #[serde(default)]
+    percent: Option<Decimal>,
+    #[serde(default)]
+    flat: Option<Decimal>,
+}
+
+impl FeeSchedule {
+    /// Sets the default fee for a transaction type.
+    pub fn set_default(&mut self, transaction_type: TransactionType, fee: Fee) {
+        self.defaults.insert(transaction_type, fee);
+    }
+
+    /// Sets the fee one client pays for a transaction type, replacing the default.
+    pub fn set_override(&mut self, client_id: u16, transaction_type: TransactionType, fee: Fee) {
+        self.overrides.insert((client_id, transaction_type), fee);
+    }
+
+    /// The fee a client pays for a row of the given type and amount (zero if none applies).
+    pub fn fee(&self, transaction_type: TransactionType, client_id: u16, amount: Decimal) -> Decimal {
+        self.overrides
+            .get(&(client_id, transaction_type))
+            .or_else(|| self.defaults.get(&transaction_type))
+            .map_or(Decimal::ZERO, |fee| fee.on(amount))
+    }
+
+    /// Reads a schedule from CSV with the columns `type,client,percent,flat`. Negative fees
+    /// would credit the client, so they are rejected.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, ConfigError> {
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+        let headers = rdr.headers()?.clone();
+        let mut schedule = Self::default();
+        for record in rdr.records() {
+            let record = record?;
+            let row: FeeRow = record.deserialize(Some(&headers))?;
+            let fee = Fee {
+                percent: row.percent.unwrap_or_default(),
+                flat: row.flat.unwrap_or_default(),
+            };
+            let line = record.position().map_or(0, |position| position.line());
+            if fee.percent < Decimal::ZERO {
+                return Err(ConfigError::Negative(line, "percent"));
+            }
+            if fee.flat < Decimal::ZERO {
+                return Err(ConfigError::Negative(line, "flat"));
+            }
+            match row.client {
+                Some(client_id) => schedule.set_override(client_id, row.transaction_type, fee),
+                None => schedule.set_default(row.transaction_type, fee),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// Loads a schedule from a CSV file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::from_reader(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_schedule_from_csv_with_overrides() {
+        let csv = "type, client, percent, flat\nwithdrawal,,1.5,0.25\nchargeback,,,15\nwithdrawal,7,0,0\n";
+        let schedule = FeeSchedule::from_reader(csv.as_bytes()).unwrap();
+        assert_eq!(schedule.fee(TransactionType::Withdrawal, 1, dec!(100)), dec!(1.75));
+        assert_eq!(schedule.fee(TransactionType::Withdrawal, 1, dec!(0.33)), dec!(0.255));
+        assert_eq!(schedule.fee(TransactionType::Withdrawal, 7, dec!(100)), dec!(0));
+        assert_eq!(schedule.fee(TransactionType::Chargeback, 7, dec!(40)), dec!(15));
+        assert_eq!(schedule.fee(TransactionType::Deposit, 1, dec!(100)), dec!(0));
+    }
+
+    #[test]
+    fn test_negative_fees_are_rejected() {
+        let csv = "type,client,percent,flat\n\
+                   withdrawal,,1,0\n\
+                   deposit,3,0,-0.5\n";
+        let result = FeeSchedule::from_reader(csv.as_bytes());
+        assert!(matches!(result, Err(ConfigError::Negative(3, "flat"))));
+        let csv = "type,client,percent,flat\n\
+                   withdrawal,,-1,\n";
+        let result = FeeSchedule::from_reader(csv.as_bytes());
+        assert!(matches!(result, Err(ConfigError::Negative(2, "percent"))));
+    }
+}
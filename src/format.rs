@@ -0,0 +1,42 @@
+use std::path::Path;
+
+/// A file format for records read or written by the engine's tooling.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DataFormat {
+    #[default]
+    Csv,
+    /// Newline-delimited JSON objects (NDJSON).
+    JsonLines,
+}
+
+impl DataFormat {
+    /// Picks JSON Lines for `.jsonl`/`.ndjson` files and CSV otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("jsonl") | Some("ndjson") => DataFormat::JsonLines,
+            _ => DataFormat::Csv,
+        }
+    }
+
+    /// Parses a format name as given on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(DataFormat::Csv),
+            "jsonl" | "ndjson" => Some(DataFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_extension() {
+        assert_eq!(DataFormat::from_path(Path::new("out.jsonl")), DataFormat::JsonLines);
+        assert_eq!(DataFormat::from_path(Path::new("out.ndjson")), DataFormat::JsonLines);
+        assert_eq!(DataFormat::from_path(Path::new("out.csv")), DataFormat::Csv);
+        assert_eq!(DataFormat::from_path(Path::new("out")), DataFormat::Csv);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::engine::PaymentEngine;
+use crate::error::HistoryError;
+use crate::format::DataFormat;
+use crate::input::{InputPosition, InputRow, RecordReader};
+use crate::models::{Account, Currency};
+use crate::snapshot::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// How many rows apart `Checkpoints` are taken unless another interval is given.
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+
+/// The file in a checkpoint directory that lists its checkpoints.
+const INDEX_FILE: &str = "index.json";
+
+/// A point in an input stream, to look at balances as they stood right after it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// The row with this number. Rows are counted from 1 as in a normal run, leaving out the
+    /// header and comments but including rows that fail to parse.
+    Row(u64),
+    /// The first row carrying this transaction ID, whether it was accepted or not.
+    Tx(u32),
+}
+
+/// Applies `rows` to `engine` in order and stops right after the row `as_of` points at.
+/// Returns the number of that row; rejected rows are skipped just as in a normal run.
+///
+/// Only the rows up to `as_of` are read, and nothing is kept beyond the engine's usual state,
+/// so the cost is that of a normal run over those rows. Repeated queries on a large input
+/// should go through `Checkpoints` instead, which start from the nearest saved state.
+pub fn replay_until<I>(engine: &mut PaymentEngine, rows: I, as_of: AsOf) -> Result<u64, HistoryError>
+where
+    I: IntoIterator<Item = io::Result<InputRow>>,
+{
+    for (index, row) in rows.into_iter().enumerate() {
+        let index = index as u64 + 1;
+        let row = row?;
+        let tx = match &row.record {
+            Ok(record) => Some(record.tx_id),
+            Err(failure) => failure.tx,
+        };
+        if let Ok(record) = row.record {
+            // A rejected row changes nothing, so it needs no further handling here.
+            let _ = engine.process(record);
+        }
+        let reached = match as_of {
+            AsOf::Row(row) => index == row,
+            AsOf::Tx(id) => tx == Some(id),
+        };
+        if reached {
+            return Ok(index);
+        }
+    }
+    Err(match as_of {
+        AsOf::Row(row) => HistoryError::RowNotReached(row),
+        AsOf::Tx(id) => HistoryError::TxNotReached(id),
+    })
+}
+
+/// The client's account in `currency` as it stood right after the row `as_of` points at,
+/// replaying `rows` on top of `engine`. `None` if the client had no account in that currency yet.
+pub fn account_as_of<I>(
+    mut engine: PaymentEngine,
+    rows: I,
+    as_of: AsOf,
+    client_id: u16,
+    currency: Currency,
+) -> Result<Option<Account>, HistoryError>
+where
+    I: IntoIterator<Item = io::Result<InputRow>>,
+{
+    replay_until(&mut engine, rows, as_of)?;
+    Ok(engine.account_in(client_id, currency).cloned())
+}
+
+/// Snapshots of the engine taken every `interval` rows of one input file, so that queries on
+/// it replay from the nearest snapshot before the point of interest instead of from the
+/// first row.
+///
+/// Checkpoints are taken while queries replay the input, so the first query pays for a replay
+/// up to its row and later ones seek straight to the nearest checkpoint in the file. The index
+/// also keeps the first row of every transaction ID read so far, which lets `AsOf::Tx` queries
+/// seek as well; it grows like the engine's ledger does. A directory belongs to one input file
+/// and starting state: rows appended to the file are picked up by later queries, but any other
+/// change to the file, or another starting state, needs a fresh directory.
+pub struct Checkpoints {
+    dir: PathBuf,
+    interval: u64,
+    index: CheckpointIndex,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CheckpointIndex {
+    /// Checkpoints in row order.
+    checkpoints: Vec<Checkpoint>,
+    /// Rows read so far, by every query together.
+    scanned: u64,
+    /// The first row carrying each transaction ID, among the rows read so far.
+    first_rows: BTreeMap<u32, u64>,
+}
+
+/// The engine state right after a row, saved as `row-<row>.json`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Checkpoint {
+    row: u64,
+    /// Where the input continues after the row.
+    position: InputPosition,
+}
+
+impl Checkpoints {
+    /// Opens the checkpoints kept in `dir`, creating the directory if needed. New checkpoints
+    /// are taken every `interval` rows, which must be positive.
+    pub fn open(dir: &Path, interval: u64) -> Result<Self, HistoryError> {
+        if interval == 0 {
+            return Err(HistoryError::ZeroInterval);
+        }
+        fs::create_dir_all(dir)?;
+        let index = match File::open(dir.join(INDEX_FILE)) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => CheckpointIndex::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            interval,
+            index,
+        })
+    }
+
+    /// Like `replay_until`, replays the input file on top of `engine`, the starting state, and
+    /// stops right after the row `as_of` points at. Starts from the last checkpoint before that
+    /// row instead, if there is one, and takes new checkpoints on the way. Returns the engine
+    /// as it stood after the row, and the row's number.
+    pub fn replay_until(
+        &mut self,
+        engine: PaymentEngine,
+        input: &Path,
+        format: DataFormat,
+        as_of: AsOf,
+    ) -> Result<(PaymentEngine, u64), HistoryError> {
+        let result = self.replay(engine, input, format, as_of);
+        // The index is saved once per query rather than at every checkpoint, since it holds
+        // every transaction ID. A checkpoint missing from it is merely taken again.
+        self.save_index()?;
+        result
+    }
+
+    fn replay(
+        &mut self,
+        engine: PaymentEngine,
+        input: &Path,
+        format: DataFormat,
+        as_of: AsOf,
+    ) -> Result<(PaymentEngine, u64), HistoryError> {
+        let target = match as_of {
+            AsOf::Row(row) => Some(row),
+            AsOf::Tx(id) => self.index.first_rows.get(&id).copied(),
+        };
+        // Without a known target row, the search continues from the last checkpoint.
+        let start = self.index.checkpoints.iter().rev().find(|c| target.is_none_or(|row| c.row <= row)).copied();
+        let (mut engine, mut rows, mut index) = match start {
+            Some(checkpoint) => {
+                let snapshot = Snapshot::load(&self.snapshot_path(checkpoint.row))?;
+                let engine = PaymentEngine::from_snapshot(snapshot, engine.config().clone());
+                if target == Some(checkpoint.row) {
+                    return Ok((engine, checkpoint.row));
+                }
+                (engine, RecordReader::resume(File::open(input)?, format, checkpoint.position)?, checkpoint.row)
+            }
+            None => (engine, RecordReader::from_path(input, format)?, 0),
+        };
+        while let Some(row) = rows.next() {
+            index += 1;
+            let row = row?;
+            let tx = match &row.record {
+                Ok(record) => Some(record.tx_id),
+                Err(failure) => failure.tx,
+            };
+            if index > self.index.scanned {
+                self.index.scanned = index;
+                if let Some(tx) = tx {
+                    self.index.first_rows.entry(tx).or_insert(index);
+                }
+            }
+            if let Ok(record) = row.record {
+                let _ = engine.process(record);
+            }
+            if index % self.interval == 0 && self.index.checkpoints.last().is_none_or(|c| c.row < index) {
+                engine.snapshot().save(&self.snapshot_path(index))?;
+                self.index.checkpoints.push(Checkpoint { row: index, position: rows.position() });
+            }
+            let reached = match as_of {
+                AsOf::Row(row) => index == row,
+                AsOf::Tx(id) => tx == Some(id),
+            };
+            if reached {
+                return Ok((engine, index));
+            }
+        }
+        Err(match as_of {
+            AsOf::Row(row) => HistoryError::RowNotReached(row),
+            AsOf::Tx(id) => HistoryError::TxNotReached(id),
+        })
+    }
+
+    /// The rows at which checkpoints were taken, in order.
+    pub fn rows(&self) -> impl Iterator<Item = u64> + '_ {
+        self.index.checkpoints.iter().map(|c| c.row)
+    }
+
+    fn snapshot_path(&self, row: u64) -> PathBuf {
+        self.dir.join(format!("row-{}.json", row))
+    }
+
+    fn save_index(&self) -> Result<(), HistoryError> {
+        let tmp_path = self.dir.join(INDEX_FILE).with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, &self.index)?;
+        writer.flush()?;
+        fs::rename(&tmp_path, self.dir.join(INDEX_FILE))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::DataFormat;
+    use crate::input::RecordReader;
+    use rust_decimal_macros::dec;
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,10.0\n\
+                         deposit,2,2,5.0\n\
+                         # not a row\n\
+                         withdrawal,1,3,4.0\n\
+                         withdrawal,1,4,100.0\n\
+                         dispute,1,1\n";
+
+    fn rows() -> RecordReader<&'static [u8]> {
+        RecordReader::new(INPUT.as_bytes(), DataFormat::Csv).unwrap()
+    }
+
+    fn available_as_of(as_of: AsOf) -> Result<Option<rust_decimal::Decimal>, HistoryError> {
+        let account = account_as_of(PaymentEngine::new(), rows(), as_of, 1, Currency::default())?;
+        Ok(account.map(|account| account.available()))
+    }
+
+    #[test]
+    fn test_account_as_of_row_and_tx() {
+        assert_eq!(available_as_of(AsOf::Row(1)).unwrap(), Some(dec!(10.0)));
+        assert_eq!(available_as_of(AsOf::Row(3)).unwrap(), Some(dec!(6.0)));
+        // A rejected row is still a point in the history.
+        assert_eq!(available_as_of(AsOf::Tx(4)).unwrap(), Some(dec!(6.0)));
+        // The first row carrying the ID is the deposit, not the later dispute.
+        assert_eq!(available_as_of(AsOf::Tx(1)).unwrap(), Some(dec!(10.0)));
+        assert_eq!(available_as_of(AsOf::Row(5)).unwrap(), Some(dec!(-4.0)));
+    }
+
+    #[test]
+    fn test_client_without_account_yet() {
+        let account = account_as_of(PaymentEngine::new(), rows(), AsOf::Row(1), 2, Currency::default()).unwrap();
+        assert_eq!(account, None);
+    }
+
+    #[test]
+    fn test_point_past_the_input_is_an_error() {
+        assert!(matches!(available_as_of(AsOf::Row(6)), Err(HistoryError::RowNotReached(6))));
+        assert!(matches!(available_as_of(AsOf::Tx(9)), Err(HistoryError::TxNotReached(9))));
+    }
+
+    #[test]
+    fn test_checkpoints_seek_to_the_nearest_snapshot() {
+        let dir = std::env::temp_dir().join(format!("payment-engine-{}-checkpoints", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let input = dir.with_extension("csv");
+        let rows: String = (1..=10).map(|tx| format!("deposit,1,{},1.0\n", tx)).collect();
+        fs::write(&input, format!("type,client,tx,amount\n{}withdrawal,1,11,4.0\n", rows)).unwrap();
+        let available = |checkpoints: &mut Checkpoints, as_of| {
+            let (engine, row) = checkpoints.replay_until(PaymentEngine::new(), &input, DataFormat::Csv, as_of).unwrap();
+            (engine.account(1).unwrap().available(), row)
+        };
+
+        let mut checkpoints = Checkpoints::open(&dir, 3).unwrap();
+        assert_eq!(available(&mut checkpoints, AsOf::Row(8)), (dec!(8.0), 8));
+        assert_eq!(checkpoints.rows().collect::<Vec<_>>(), [3, 6]);
+        // Rewriting the first row in place shows that later queries no longer read it.
+        let text = fs::read_to_string(&input).unwrap().replacen("deposit,1,1,1.0", "deposit,1,1,9.0", 1);
+        fs::write(&input, text).unwrap();
+        assert_eq!(available(&mut checkpoints, AsOf::Row(4)), (dec!(4.0), 4));
+        assert_eq!(available(&mut checkpoints, AsOf::Row(6)), (dec!(6.0), 6));
+
+        // The index survives reopening, and IDs first read past it are searched for from its end.
+        let mut checkpoints = Checkpoints::open(&dir, 3).unwrap();
+        assert_eq!(available(&mut checkpoints, AsOf::Tx(7)), (dec!(7.0), 7));
+        assert_eq!(available(&mut checkpoints, AsOf::Tx(11)), (dec!(6.0), 11));
+        assert_eq!(checkpoints.rows().collect::<Vec<_>>(), [3, 6, 9]);
+        let result = checkpoints.replay_until(PaymentEngine::new(), &input, DataFormat::Csv, AsOf::Tx(12));
+        assert!(matches!(result, Err(HistoryError::TxNotReached(12))));
+        assert!(matches!(Checkpoints::open(&dir, 0), Err(HistoryError::ZeroInterval)));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&input).unwrap();
+    }
+}
@@ -0,0 +1,461 @@
+use crate::format::DataFormat;
+use crate::models::InputRecord;
+use csv::{ByteRecord, StringRecord};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// One row read from an input stream.
+#[derive(Debug)]
+pub struct InputRow {
+    /// The line of the input the row starts on.
+    pub line: u64,
+    /// The row as it appeared in the input; empty unless raw capture is enabled.
+    pub raw: String,
+    /// The parsed record, or why it could not be parsed.
+    pub record: Result<InputRecord, ParseFailure>,
+}
+
+/// Where a `RecordReader` stands in its input: the last line read and the byte offset after it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputPosition {
+    pub line: u64,
+    pub offset: u64,
+}
+
+/// A row that could not be parsed into an `InputRecord`.
+#[derive(Debug, PartialEq)]
+pub struct ParseFailure {
+    /// The client ID, when it could still be read.
+    pub client: Option<u16>,
+    /// The transaction ID, when it could still be read.
+    pub tx: Option<u32>,
+    pub message: String,
+}
+
+/// Streams `InputRow`s from CSV or JSON Lines input, one row at a time.
+///
+/// CSV input has a header row, may contain `#` comment lines and has its fields trimmed; rows
+/// may leave out trailing columns, and quoted fields may span lines. JSON Lines input holds one
+/// object per line with the same field names as the CSV header; `amount` may be given as a
+/// string or a number. Blank lines are skipped in both formats.
+pub struct RecordReader<R: Read> {
+    source: Source<R>,
+    capture_raw: bool,
+}
+
+/// The columns assumed for CSV input that has no header row.
+pub const DEFAULT_CSV_HEADER: &str = "type,client,tx,amount,currency,counterparty,timestamp,reason";
+
+enum Source<R: Read> {
+    Csv(CsvInput<R>),
+    Json(JsonInput<R>),
+}
+
+struct CsvInput<R: Read> {
+    reader: csv::Reader<Tee<R>>,
+    headers: StringRecord,
+    client_column: Option<usize>,
+    tx_column: Option<usize>,
+    /// A data row read while looking for a header, not returned yet.
+    pending: Option<CsvRow>,
+}
+
+struct CsvRow {
+    fields: StringRecord,
+    line: u64,
+    raw: String,
+    /// Where the reader stood before the row.
+    before: InputPosition,
+}
+
+struct JsonInput<R: Read> {
+    reader: BufReader<R>,
+    line: u64,
+    /// Bytes read so far.
+    offset: u64,
+    text: String,
+}
+
+/// Passes the input through to the CSV parser while keeping the bytes it has not finished
+/// with, so every row can be given its exact line and raw text.
+struct Tee<R> {
+    inner: R,
+    kept: Vec<u8>,
+    /// The offset at which `kept` starts.
+    start: u64,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.kept.extend_from_slice(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for Tee<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.start = self.inner.seek(pos)?;
+        self.kept.clear();
+        Ok(self.start)
+    }
+}
+
+impl<R> Tee<R> {
+    /// Returns the bytes between two offsets and forgets everything before `end`.
+    fn take(&mut self, begin: u64, end: u64) -> Vec<u8> {
+        let (begin, end) = ((begin - self.start) as usize, (end - self.start) as usize);
+        let bytes = self.kept[begin..end].to_vec();
+        self.kept.drain(..end);
+        self.start += end as u64;
+        bytes
+    }
+}
+
+impl RecordReader<File> {
+    /// Opens an input file in the given format.
+    pub fn from_path(path: &Path, format: DataFormat) -> io::Result<Self> {
+        Self::new(File::open(path)?, format)
+    }
+}
+
+impl<R: Read> RecordReader<R> {
+    /// Creates a reader over `reader`. For CSV this reads the header row.
+    pub fn new(reader: R, format: DataFormat) -> io::Result<Self> {
+        Self::open(reader, format, false)
+    }
+
+    /// Creates a CSV reader for input that may start without a header row. If the first row
+    /// does not start with a `type` column, the columns of `DEFAULT_CSV_HEADER` are assumed and
+    /// the row is read as data.
+    pub fn csv_with_optional_header(reader: R) -> io::Result<Self> {
+        Self::open(reader, DataFormat::Csv, true)
+    }
+
+    fn open(reader: R, format: DataFormat, header_optional: bool) -> io::Result<Self> {
+        let source = match format {
+            DataFormat::Csv => Source::Csv(CsvInput::open(reader, header_optional)?),
+            DataFormat::JsonLines => Source::Json(JsonInput {
+                reader: BufReader::new(reader),
+                line: 0,
+                offset: 0,
+                text: String::new(),
+            }),
+        };
+        Ok(Self {
+            source,
+            capture_raw: false,
+        })
+    }
+
+    /// Keeps the raw text of every row in `InputRow::raw`, e.g. for a rejections report.
+    pub fn capture_raw(mut self, capture: bool) -> Self {
+        self.capture_raw = capture;
+        self
+    }
+
+    /// The position right after the last row returned, to `resume` from later.
+    pub fn position(&self) -> InputPosition {
+        match &self.source {
+            // The row read while looking for a header has not been returned yet.
+            Source::Csv(CsvInput { pending: Some(row), .. }) => row.before,
+            Source::Csv(csv) => to_input_position(csv.reader.position()),
+            Source::Json(json) => InputPosition { line: json.line, offset: json.offset },
+        }
+    }
+}
+
+impl<R: Read + Seek> RecordReader<R> {
+    /// Creates a reader that continues from `position`, as returned by `position` on a reader
+    /// over the same input. For CSV the header row is read from the start first.
+    pub fn resume(reader: R, format: DataFormat, position: InputPosition) -> io::Result<Self> {
+        let mut rdr = Self::new(reader, format)?;
+        match &mut rdr.source {
+            Source::Csv(csv) => {
+                let mut start = csv::Position::new();
+                start.set_byte(position.offset).set_line(position.line + 1);
+                csv.reader.seek(start)?;
+            }
+            Source::Json(json) => {
+                json.reader.seek(SeekFrom::Start(position.offset))?;
+                json.line = position.line;
+                json.offset = position.offset;
+            }
+        }
+        Ok(rdr)
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = io::Result<InputRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = match &mut self.source {
+            Source::Csv(csv) => csv.next_row().transpose().map(|row| {
+                row.map(|row| InputRow {
+                    line: row.line,
+                    raw: row.raw,
+                    record: csv.parse(row.fields),
+                })
+            }),
+            Source::Json(json) => json.next_row(),
+        };
+        row.map(|row| {
+            row.map(|mut row| {
+                if !self.capture_raw {
+                    row.raw.clear();
+                }
+                row
+            })
+        })
+    }
+}
+
+/// Turns a position of the CSV parser, which points at the start of the next record, into the
+/// last line read and the offset after it.
+fn to_input_position(position: &csv::Position) -> InputPosition {
+    InputPosition { line: position.line() - 1, offset: position.byte() }
+}
+
+impl<R: Read> CsvInput<R> {
+    fn open(reader: R, header_optional: bool) -> io::Result<Self> {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .comment(Some(b'#'))
+            .from_reader(Tee { inner: reader, kept: Vec::new(), start: 0 });
+        let mut csv = Self {
+            reader,
+            headers: StringRecord::new(),
+            client_column: None,
+            tx_column: None,
+            pending: None,
+        };
+        if let Some(first) = csv.next_row()? {
+            match header_optional && first.fields.get(0) != Some("type") {
+                true => {
+                    csv.headers = DEFAULT_CSV_HEADER.split(',').collect();
+                    csv.pending = Some(first);
+                }
+                false => csv.headers = first.fields,
+            }
+        }
+        let column = |name: &str| csv.headers.iter().position(|h| h == name);
+        (csv.client_column, csv.tx_column) = (column("client"), column("tx"));
+        Ok(csv)
+    }
+
+    /// Reads the next row that holds data, skipping lines that hold only whitespace.
+    fn next_row(&mut self) -> io::Result<Option<CsvRow>> {
+        if let Some(row) = self.pending.take() {
+            return Ok(Some(row));
+        }
+        let mut record = ByteRecord::new();
+        loop {
+            let before = self.reader.position().clone();
+            if !self.reader.read_byte_record(&mut record)? {
+                return Ok(None);
+            }
+            let end = self.reader.position().byte();
+            let bytes = self.reader.get_mut().take(before.byte(), end);
+            let mut fields = StringRecord::from_byte_record_lossy(record.clone());
+            // Trim whitespace to handle variations in input formatting.
+            fields.trim();
+            if fields.len() == 1 && fields[0].is_empty() {
+                continue;
+            }
+            // The parser's position is where it started looking, before any blank or comment
+            // lines; the row itself starts after them.
+            let (skipped, lines) = skipped_lines(&bytes);
+            let raw = String::from_utf8_lossy(&bytes[skipped..]);
+            return Ok(Some(CsvRow {
+                fields,
+                line: before.line() + lines,
+                raw: raw.trim_end_matches(['\r', '\n']).to_string(),
+                before: to_input_position(&before),
+            }));
+        }
+    }
+
+    /// Parses one CSV data row against the header row.
+    fn parse(&self, record: StringRecord) -> Result<InputRecord, ParseFailure> {
+        record.deserialize::<InputRecord>(Some(&self.headers)).map_err(|e| {
+            let field = |column: Option<usize>| column.and_then(|i| record.get(i));
+            ParseFailure {
+                client: field(self.client_column).and_then(|f| f.parse().ok()),
+                tx: field(self.tx_column).and_then(|f| f.parse().ok()),
+                message: e.to_string(),
+            }
+        })
+    }
+}
+
+/// Measures the blank and `#` comment lines the CSV parser passes over before a record,
+/// returning their length in bytes and the number of lines they end.
+fn skipped_lines(bytes: &[u8]) -> (usize, u64) {
+    let (mut at, mut lines) = (0, 0);
+    loop {
+        let len = match bytes.get(at) {
+            Some(b'\r' | b'\n') => 1,
+            Some(b'#') => bytes[at..].iter().position(|&b| b == b'\n').map_or(bytes.len() - at, |i| i + 1),
+            _ => return (at, lines),
+        };
+        lines += bytes[at..at + len].ends_with(b"\n") as u64;
+        at += len;
+    }
+}
+
+impl<R: Read> JsonInput<R> {
+    /// Reads and parses the next line that is not blank.
+    fn next_row(&mut self) -> Option<io::Result<InputRow>> {
+        loop {
+            self.text.clear();
+            let read = match self.reader.read_line(&mut self.text) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            self.offset += read as u64;
+            let text = self.text.trim_end_matches(['\r', '\n']);
+            if !text.trim().is_empty() {
+                return Some(Ok(InputRow {
+                    line: self.line,
+                    raw: text.to_string(),
+                    record: parse_json_record(text),
+                }));
+            }
+        }
+    }
+}
+
+/// Parses one JSON Lines object.
+fn parse_json_record(text: &str) -> Result<InputRecord, ParseFailure> {
+    let value: Value = serde_json::from_str(text).map_err(|e| ParseFailure {
+        client: None,
+        tx: None,
+        message: e.to_string(),
+    })?;
+    parse_json_value(value)
+}
+
+/// Converts a JSON object with the input field names into a record. Numeric amounts are
+/// converted to their exact decimal text first, since `Decimal` only deserializes from strings.
+pub(crate) fn parse_json_value(mut value: Value) -> Result<InputRecord, ParseFailure> {
+    let failure = |value: &Value, message: String| ParseFailure {
+        client: value.get("client").and_then(Value::as_u64).and_then(|c| c.try_into().ok()),
+        tx: value.get("tx").and_then(Value::as_u64).and_then(|t| t.try_into().ok()),
+        message,
+    };
+    if let Some(amount) = value.get_mut("amount") {
+        if let Value::Number(number) = amount {
+            *amount = Value::String(number.to_string());
+        }
+    }
+    serde_json::from_value(value.clone()).map_err(|e| failure(&value, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn read(input: &str, format: DataFormat) -> Vec<InputRow> {
+        RecordReader::new(input.as_bytes(), format).unwrap().capture_raw(true).map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_csv_rows_are_trimmed_and_numbered() {
+        let rows = read("type, client, tx, amount\r\n# comment\r\n\r\ndeposit, 1, 1,\"1.5\"\r\n#\nwithdrawal,x,2,1", DataFormat::Csv);
+        assert_eq!(rows[0].line, 4);
+        assert_eq!(rows[0].raw, "deposit, 1, 1,\"1.5\"");
+        assert_eq!(rows[0].record, Ok(InputRecord::deposit(1, 1, dec!(1.5))));
+        assert_eq!(rows[1].line, 6);
+        let failure = rows[1].record.as_ref().unwrap_err();
+        assert_eq!((failure.client, failure.tx), (None, Some(2)));
+    }
+
+    #[test]
+    fn test_csv_quoted_fields_may_span_lines() {
+        let input = "type,client,tx,amount,currency,counterparty,timestamp,reason\n\
+                     freeze,1,1,,,,,\"chargeback\r\nreview\"\n\
+                     # comment\n\
+                     deposit,1,2,1.0\n";
+        let rows = read(input, DataFormat::Csv);
+        assert_eq!(rows[0].line, 2);
+        assert_eq!(rows[0].raw, "freeze,1,1,,,,,\"chargeback\r\nreview\"");
+        assert_eq!(rows[0].record, Ok(InputRecord::admin(TransactionType::Freeze, 1, 1, "chargeback\r\nreview")));
+        assert_eq!(rows[1].line, 5);
+        assert_eq!(rows[1].record, Ok(InputRecord::deposit(1, 2, dec!(1.0))));
+    }
+
+    #[test]
+    fn test_json_lines_accepts_string_and_number_amounts() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.2345\"}\n\n\
+                     {\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":123456789.123456789}\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1}\n";
+        let rows = read(input, DataFormat::JsonLines);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].record.as_ref().unwrap().amount, Some(dec!(1.2345)));
+        assert_eq!(rows[1].line, 3);
+        assert_eq!(rows[1].record.as_ref().unwrap().amount, Some(dec!(123456789.123456789)));
+        assert_eq!(rows[2].record.as_ref().unwrap().amount, None);
+    }
+
+    #[test]
+    fn test_json_lines_parse_failures_keep_ids() {
+        let rows = read("{\"type\":\"refund\",\"client\":7,\"tx\":9}\nnot json\n", DataFormat::JsonLines);
+        let failure = rows[0].record.as_ref().unwrap_err();
+        assert_eq!((failure.client, failure.tx), (Some(7), Some(9)));
+        assert_eq!(rows[1].raw, "not json");
+        assert!(rows[1].record.is_err());
+    }
+
+    #[test]
+    fn test_csv_header_may_be_omitted() {
+        let input = "deposit,1,1,2.5\ndispute,1,1\n";
+        let rdr = RecordReader::csv_with_optional_header(input.as_bytes()).unwrap();
+        assert_eq!(rdr.position(), InputPosition::default());
+        let rows: Vec<InputRow> = rdr.map(Result::unwrap).collect();
+        assert_eq!(rows[0].line, 1);
+        assert_eq!(rows[0].record, Ok(InputRecord::deposit(1, 1, dec!(2.5))));
+        assert_eq!(rows[1].record, Ok(InputRecord::dispute(1, 1)));
+
+        let input = "type,client,tx,amount\nwithdrawal,1,2,1.0\n";
+        let rows: Vec<InputRow> = RecordReader::csv_with_optional_header(input.as_bytes()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].record, Ok(InputRecord::withdrawal(1, 2, dec!(1.0))));
+    }
+
+    #[test]
+    fn test_resume_from_position() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\n# comment\r\ndeposit,1,2,2.0\ndeposit,1,3,3.0\n";
+        let mut rdr = RecordReader::new(input.as_bytes(), DataFormat::Csv).unwrap();
+        rdr.next();
+        rdr.next();
+        let position = rdr.position();
+        assert_eq!(position, InputPosition { line: 4, offset: 65 });
+
+        let rows: Vec<InputRow> = RecordReader::resume(io::Cursor::new(input), DataFormat::Csv, position)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].line, 5);
+        assert_eq!(rows[0].record, Ok(InputRecord::deposit(1, 3, dec!(3.0))));
+    }
+
+    #[test]
+    fn test_csv_optional_columns() {
+        let rows = read("type,client,tx,amount,currency,counterparty,timestamp\n\
+                         transfer,1,1,2.5,eur,2,1700000000\n\
+                         dispute,2,1,,,,\n", DataFormat::Csv);
+        let expected = InputRecord::transfer(1, 1, 2, dec!(2.5)).in_currency("EUR".parse().unwrap()).at(1_700_000_000);
+        assert_eq!(rows[0].record, Ok(expected));
+        assert_eq!(rows[1].record, Ok(InputRecord::dispute(2, 1)));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::engine::{PaymentEngine, Saved, Transfer};
+use crate::error::InvariantViolation;
+use crate::models::{Currency, InputRecord, TransactionDirection, TransactionRecord};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// What a ledger entry has added to its client's total so far.
+fn flow(tx: &TransactionRecord) -> Decimal {
+    match tx.direction {
+        // A deposit or a received transfer, less what was charged back.
+        TransactionDirection::Credit => tx.amount - tx.charged_back,
+        // A withdrawal or a sent transfer, less what disputes and chargebacks re-credited.
+        TransactionDirection::Debit => tx.disputed + tx.charged_back - tx.amount,
+    }
+}
+
+/// Every ledger entry, including the receiving legs of transfers.
+fn entries(engine: &PaymentEngine) -> impl Iterator<Item = &TransactionRecord> {
+    engine.ledger().chain(engine.transfer_credits()).map(|(_, tx)| tx)
+}
+
+/// Checks the whole state against every invariant:
+/// - no account holds a negative amount,
+/// - each account holds exactly what is under dispute of its client's transactions in its currency,
+/// - in each currency, the balances add up to the deposits less withdrawals, chargebacks and
+///   fees, with disputed withdrawals counted back in. Transfers move money between clients and
+///   add nothing.
+///
+/// Violations are ordered by client and currency, followed by those of whole currencies.
+pub fn audit(engine: &PaymentEngine) -> Vec<InvariantViolation> {
+    let mut disputed: BTreeMap<(u16, Currency), Decimal> = BTreeMap::new();
+    let mut flows: BTreeMap<Currency, Decimal> = BTreeMap::new();
+    for tx in entries(engine) {
+        *disputed.entry((tx.client_id, tx.currency)).or_default() += tx.disputed;
+        *flows.entry(tx.currency).or_default() += flow(tx);
+    }
+    for (currency, fees) in engine.fee_revenue() {
+        *flows.entry(currency).or_default() -= fees;
+    }
+
+    let mut keys: BTreeSet<(u16, Currency)> = engine.accounts().map(|(client, currency, _)| (client, currency)).collect();
+    keys.extend(disputed.keys().copied());
+    let mut balances: BTreeMap<Currency, Decimal> = BTreeMap::new();
+    let mut violations = Vec::new();
+    for (client, currency) in keys {
+        let (held, total) = engine
+            .account_in(client, currency)
+            .map_or((Decimal::ZERO, Decimal::ZERO), |account| (account.held(), account.total()));
+        *balances.entry(currency).or_default() += total;
+        let disputed = disputed.get(&(client, currency)).copied().unwrap_or_default();
+        if held < Decimal::ZERO {
+            violations.push(InvariantViolation::NegativeHeld(client, currency, held));
+        }
+        if held != disputed {
+            violations.push(InvariantViolation::HeldMismatch(client, currency, held, disputed));
+        }
+    }
+    let currencies: BTreeSet<Currency> = balances.keys().chain(flows.keys()).copied().collect();
+    for currency in currencies {
+        let balance = balances.get(&currency).copied().unwrap_or_default();
+        let flow = flows.get(&currency).copied().unwrap_or_default();
+        if balance != flow {
+            violations.push(InvariantViolation::FundsNotConserved(currency, balance, flow));
+        }
+    }
+    violations
+}
+
+/// The parts of the state a record can change, captured before it is applied so that
+/// `verify` can check the change afterwards and `roll_back` can undo it.
+///
+/// A record only touches its own ledger entries and the accounts of its client (and of the
+/// counterparty of a transfer), so the invariants are checked on what changed: the touched
+/// accounts must still hold what is under dispute, and their totals must have changed by what
+/// the touched entries and the fee revenue say. That keeps the cost per record independent of
+/// the size of the state; the full state is only scanned to report a violation, and to find the
+/// balances of a client that an admin row without a currency applies to.
+pub(crate) struct Probe {
+    tx_id: u32,
+    keys: Vec<(u16, Currency)>,
+    before: Reading,
+    saved: Saved,
+}
+
+/// The amounts a `Probe` compares.
+struct Reading {
+    /// Held funds and total of each touched account.
+    accounts: HashMap<(u16, Currency), (Decimal, Decimal)>,
+    /// Amount under dispute of the touched entries, per client and currency.
+    disputed: HashMap<(u16, Currency), Decimal>,
+    /// What the touched entries added to the balances, per currency.
+    flows: HashMap<Currency, Decimal>,
+    fees: HashMap<Currency, Decimal>,
+}
+
+impl Probe {
+    pub(crate) fn capture(engine: &PaymentEngine, record: &InputRecord) -> Self {
+        let mut keys = vec![(record.client_id, record.currency)];
+        keys.extend(record.counterparty.map(|to| (to, record.currency)));
+        if record.transaction_type.is_admin() {
+            keys.extend(
+                engine
+                    .accounts()
+                    .filter(|(client, currency, _)| *client == record.client_id && *currency != record.currency)
+                    .map(|(client, currency, _)| (client, currency)),
+            );
+        }
+        Self::new(engine, record.tx_id, keys)
+    }
+
+    /// Captures the state before one leg of a transfer that `ParallelEngine` applies on the
+    /// shard of the sending or of the receiving client.
+    pub(crate) fn capture_transfer(engine: &PaymentEngine, transfer: &Transfer) -> Self {
+        let keys = vec![(transfer.from, transfer.currency), (transfer.to, transfer.currency)];
+        Self::new(engine, transfer.tx_id, keys)
+    }
+
+    fn new(engine: &PaymentEngine, tx_id: u32, mut keys: Vec<(u16, Currency)>) -> Self {
+        // The record can only create entries for the accounts given, so existing entries are
+        // the only ones that may add accounts.
+        for tx in engine.transaction(tx_id).into_iter().chain(engine.transfer_credit(tx_id)) {
+            if !keys.contains(&(tx.client_id, tx.currency)) {
+                keys.push((tx.client_id, tx.currency));
+            }
+        }
+        let mut probe = Self {
+            tx_id,
+            saved: engine.save(tx_id, &keys),
+            keys,
+            before: Reading::empty(),
+        };
+        probe.before = probe.read(engine);
+        probe
+    }
+
+    /// Checks the change made by the record, returning the first invariant it broke.
+    fn verify(&self, engine: &PaymentEngine) -> Result<(), InvariantViolation> {
+        let after = self.read(engine);
+        let read = |reading: &Reading, key| reading.accounts.get(key).copied().unwrap_or_default();
+        let mut total_changes: HashMap<Currency, Decimal> = HashMap::new();
+        for key in &self.keys {
+            let ((held_before, total_before), (held, total)) = (read(&self.before, key), read(&after, key));
+            let disputed_before = self.before.disputed.get(key).copied().unwrap_or_default();
+            let disputed = after.disputed.get(key).copied().unwrap_or_default();
+            if held < Decimal::ZERO {
+                return Err(InvariantViolation::NegativeHeld(key.0, key.1, held));
+            }
+            if held - held_before != disputed - disputed_before {
+                let disputed = entries(engine)
+                    .filter(|tx| (tx.client_id, tx.currency) == *key)
+                    .map(|tx| tx.disputed)
+                    .sum();
+                return Err(InvariantViolation::HeldMismatch(key.0, key.1, held, disputed));
+            }
+            *total_changes.entry(key.1).or_default() += total - total_before;
+        }
+        for (currency, change) in total_changes {
+            let flow_change = |reading: &Reading| {
+                reading.flows.get(&currency).copied().unwrap_or_default()
+                    - reading.fees.get(&currency).copied().unwrap_or_default()
+            };
+            if change != flow_change(&after) - flow_change(&self.before) {
+                // Report the whole currency, as `audit` would.
+                let violation = audit(engine)
+                    .into_iter()
+                    .find(|v| matches!(v, InvariantViolation::FundsNotConserved(c, _, _) if *c == currency));
+                return Err(violation.unwrap_or(InvariantViolation::FundsNotConserved(currency, change, change)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Undoes the record, putting back the state captured before it was applied.
+    pub(crate) fn roll_back(self, engine: &mut PaymentEngine) {
+        engine.restore(self.saved);
+    }
+
+    /// Verifies the change and undoes it if it broke an invariant. A change that stands hands
+    /// the probe back, so that it can still be rolled back later.
+    pub(crate) fn check(self, engine: &mut PaymentEngine) -> Result<Self, InvariantViolation> {
+        match self.verify(engine) {
+            Ok(()) => Ok(self),
+            Err(violation) => {
+                self.roll_back(engine);
+                Err(violation)
+            }
+        }
+    }
+
+    fn entries<'a>(&self, engine: &'a PaymentEngine) -> impl Iterator<Item = &'a TransactionRecord> {
+        engine.transaction(self.tx_id).into_iter().chain(engine.transfer_credit(self.tx_id))
+    }
+
+    fn read(&self, engine: &PaymentEngine) -> Reading {
+        let mut reading = Reading::empty();
+        for (client, currency) in &self.keys {
+            if let Some(account) = engine.account_in(*client, *currency) {
+                reading.accounts.insert((*client, *currency), (account.held(), account.total()));
+            }
+        }
+        for tx in self.entries(engine) {
+            *reading.disputed.entry((tx.client_id, tx.currency)).or_default() += tx.disputed;
+            *reading.flows.entry(tx.currency).or_default() += flow(tx);
+        }
+        reading.fees = engine.fee_revenue().into_iter().collect();
+        reading
+    }
+}
+
+impl Reading {
+    fn empty() -> Self {
+        Self {
+            accounts: HashMap::new(),
+            disputed: HashMap::new(),
+            flows: HashMap::new(),
+            fees: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::events::{Event, MemorySink};
+    use crate::fees::{Fee, FeeSchedule};
+    use crate::models::TransactionType;
+    use crate::snapshot::Snapshot;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_every_record_type_keeps_the_invariants() {
+        let mut fees = FeeSchedule::default();
+        fees.set_default(TransactionType::Withdrawal, Fee { percent: dec!(1), flat: dec!(0.1) });
+        fees.set_default(TransactionType::Dispute, Fee { percent: dec!(0), flat: dec!(2) });
+        let mut engine = PaymentEngine::with_config(EngineConfig { fees, currencies: true, ..EngineConfig::default() });
+        engine.set_invariant_checks(true);
+        let eur: Currency = "EUR".parse().unwrap();
+        let records = [
+            InputRecord::deposit(1, 1, dec!(100.0)),
+            InputRecord::deposit(2, 2, dec!(50.0)).in_currency(eur),
+            InputRecord::withdrawal(1, 3, dec!(10.0)),
+            InputRecord::transfer(1, 4, 2, dec!(20.0)),
+            InputRecord::dispute(1, 3),
+            InputRecord::chargeback(1, 3),
+            InputRecord::dispute(2, 4),
+            InputRecord::resolve(2, 4),
+            InputRecord::dispute(2, 2),
+            InputRecord::chargeback(2, 2),
+            InputRecord::admin(TransactionType::Unlock, 2, 5, "ok"),
+        ];
+        for record in records {
+            engine.process(record).unwrap();
+        }
+        // Rejections change nothing and are reported as such.
+        let result = engine.process(InputRecord::chargeback(2, 2));
+        assert_eq!(result, Err(crate::error::EngineError::TransactionChargedBack(2)));
+        assert_eq!(audit(&engine), vec![]);
+    }
+
+    #[test]
+    fn test_audit_finds_an_inconsistent_snapshot() {
+        let mut engine = PaymentEngine::new();
+        engine.process(InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        engine.process(InputRecord::dispute(1, 1)).unwrap();
+        let mut snapshot: Snapshot = engine.snapshot();
+        snapshot.accounts[0].held = dec!(-1.0);
+        let engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        let unspecified = Currency::default();
+        assert_eq!(
+            audit(&engine),
+            vec![
+                InvariantViolation::NegativeHeld(1, unspecified, dec!(-1.0)),
+                InvariantViolation::HeldMismatch(1, unspecified, dec!(-1.0), dec!(10.0)),
+                InvariantViolation::FundsNotConserved(unspecified, dec!(-1.0), dec!(10.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_a_violating_record_is_rolled_back() {
+        let mut engine = PaymentEngine::new();
+        engine.process(InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+        let mut snapshot = engine.snapshot();
+        snapshot.accounts[0].held = dec!(-1.0);
+        let mut engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        engine.set_invariant_checks(true);
+        let events = MemorySink::default();
+        engine.set_event_sink(Box::new(events.clone()));
+        let result = engine.process(InputRecord::deposit(1, 2, dec!(1.0)));
+        assert_eq!(
+            result,
+            Err(crate::error::EngineError::InvariantViolated(InvariantViolation::NegativeHeld(
+                1,
+                Currency::default(),
+                dec!(-1.0)
+            )))
+        );
+        assert_eq!(engine.account(1).unwrap().total(), dec!(9.0));
+        assert!(engine.transaction(2).is_none());
+        assert!(matches!(events.events()[..], [Event::TransactionRejected { tx: 2, .. }]));
+        // Nothing else was left behind, so the same row is rejected again rather than replayed.
+        assert!(engine.process(InputRecord::deposit(1, 2, dec!(1.0))).is_err());
+        assert_eq!(engine.replays(), 0);
+    }
+}
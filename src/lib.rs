@@ -0,0 +1,64 @@
+//! A streaming payments engine.
+//!
+//! The engine consumes `InputRecord`s one at a time, maintains the state of every client
+//! `Account` and the ledger of disputable transactions, and reports the final account states
+//! as `OutputRecord`s. It has no knowledge of files or standard I/O, so it can be embedded in
+//! any application.
+//!
+//! ```
+//! use payment_engine::{InputRecord, PaymentEngine};
+//! use rust_decimal_macros::dec;
+//!
+//! let mut engine = PaymentEngine::new();
+//! engine.process(InputRecord::deposit(1, 1, dec!(10.0))).unwrap();
+//!
+//! let account = engine.account(1).unwrap();
+//! assert_eq!(account.available(), dec!(10.0));
+//! assert!(!account.locked());
+//! ```
+
+pub mod config;
+pub mod engine;
+pub mod error;
+pub mod events;
+pub mod fees;
+pub mod format;
+pub mod history;
+pub mod input;
+pub mod invariants;
+pub mod limits;
+pub mod listener;
+pub mod models;
+pub mod parallel;
+pub mod reconcile;
+pub mod rejections;
+pub mod server;
+pub mod snapshot;
+pub mod statement;
+pub mod wal;
+
+pub use config::{EngineConfig, OverLimitPolicy, RedisputePolicy};
+pub use engine::PaymentEngine;
+pub use error::{
+    AppError, ConfigError, EngineError, HistoryError, InvariantViolation, ReconcileError, SnapshotError,
+    WalError,
+};
+pub use events::{Event, EventSink, JsonLinesSink, MemorySink};
+pub use fees::{Fee, FeeSchedule};
+pub use format::DataFormat;
+pub use history::{account_as_of, replay_until, AsOf, Checkpoints, DEFAULT_CHECKPOINT_INTERVAL};
+pub use input::{InputPosition, InputRow, ParseFailure, RecordReader, DEFAULT_CSV_HEADER};
+pub use invariants::audit;
+pub use limits::CreditLimits;
+pub use listener::LineListener;
+pub use models::{
+    Account, AccountStatus, AdminAction, Currency, InputRecord, OutputOrder, OutputRecord,
+    TransactionDirection, TransactionRecord, TransactionStatus, TransactionType,
+};
+pub use parallel::{ParallelEngine, ParallelOutcome};
+pub use reconcile::{reconcile, write_discrepancies, Discrepancy, ExpectedAccount, ExpectedAccounts, Issue};
+pub use rejections::{Rejection, RejectionWriter};
+pub use server::{ApiError, Server};
+pub use snapshot::Snapshot;
+pub use statement::{write_statement, Activity, StatementGenerator, StatementLine};
+pub use wal::{Recovery, WalEngine, WriteAheadLog};
@@ -0,0 +1,85 @@
+use crate::error::ConfigError;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Agreed overdraft lines: how far below zero a client's `available` balance may go.
+///
+/// A limit applies to each of the client's balances. Clients without a limit cannot overdraw.
+#[derive(Debug, Clone, Default)]
+pub struct CreditLimits {
+    limits: HashMap<u16, Decimal>,
+}
+
+/// One row of a credit limit file.
+#[derive(Debug, Deserialize)]
+struct LimitRow {
+    client: u16,
+    limit: Decimal,
+}
+
+impl CreditLimits {
+    /// Sets a client's credit limit.
+    pub fn set(&mut self, client_id: u16, limit: Decimal) {
+        self.limits.insert(client_id, limit);
+    }
+
+    /// The client's credit limit (zero if none was agreed).
+    pub fn limit(&self, client_id: u16) -> Decimal {
+        self.limits.get(&client_id).copied().unwrap_or_default()
+    }
+
+    /// Whether no client has a credit limit.
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty()
+    }
+
+    /// Reads limits from CSV with the columns `client,limit`. A negative limit would block
+    /// withdrawals the balance covers, so it is rejected.
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, ConfigError> {
+        let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+        let headers = rdr.headers()?.clone();
+        let mut limits = Self::default();
+        for record in rdr.records() {
+            let record = record?;
+            let row: LimitRow = record.deserialize(Some(&headers))?;
+            if row.limit < Decimal::ZERO {
+                return Err(ConfigError::Negative(record.position().map_or(0, |position| position.line()), "limit"));
+            }
+            limits.set(row.client, row.limit);
+        }
+        Ok(limits)
+    }
+
+    /// Loads limits from a CSV file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        Self::from_reader(File::open(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_limits_from_csv() {
+        let limits = CreditLimits::from_reader("client, limit\n1, 500\n2,0.5\n".as_bytes()).unwrap();
+        assert_eq!(limits.limit(1), dec!(500));
+        assert_eq!(limits.limit(2), dec!(0.5));
+        assert_eq!(limits.limit(3), dec!(0));
+        assert!(!limits.is_empty());
+    }
+
+    #[test]
+    fn test_negative_limit_is_rejected() {
+        let csv = "client,limit\n\
+                   1,500\n\
+                   2,-10\n";
+        let result = CreditLimits::from_reader(csv.as_bytes());
+        assert!(matches!(result, Err(ConfigError::Negative(3, "limit"))));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::engine::PaymentEngine;
+use crate::input::RecordReader;
+use crate::rejections::PARSE_ERROR;
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// The open connections, by connection number, so `shutdown` can close and wait for them.
+type Connections = Arc<Mutex<HashMap<u64, (TcpStream, JoinHandle<()>)>>>;
+
+/// Accepts CSV rows over plain TCP and applies them to one shared `PaymentEngine`.
+///
+/// Each connection sends CSV rows, optionally preceded by a header row; without one the columns
+/// are `DEFAULT_CSV_HEADER`. Every data row is answered with a line of its own:
+/// - `OK <line>` once it was applied (`OK <line> replayed` for an exact replay),
+/// - `ERR <line> <error> <message>` when it was rejected, with the `EngineError` variant name
+///   or `ParseError`. A row undone for breaking an invariant is rejected as `InvariantViolated`.
+///
+/// Lines are counted from 1 on each connection. Blank lines, comments and the header get no
+/// reply. Connections are handled on threads of their own; the engine is behind a mutex, so
+/// rows are still applied one at a time.
+pub struct LineListener {
+    addr: SocketAddr,
+    engine: Arc<Mutex<PaymentEngine>>,
+    stopping: Arc<AtomicBool>,
+    connections: Connections,
+    acceptor: JoinHandle<()>,
+}
+
+impl LineListener {
+    /// Binds to `addr` and starts accepting connections.
+    pub fn start(addr: impl ToSocketAddrs, engine: PaymentEngine) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let engine = Arc::new(Mutex::new(engine));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let connections: Connections = Arc::default();
+        let acceptor = {
+            let (engine, stopping, connections) = (Arc::clone(&engine), Arc::clone(&stopping), Arc::clone(&connections));
+            thread::spawn(move || accept(listener, engine, stopping, connections))
+        };
+        Ok(Self {
+            addr,
+            engine,
+            stopping,
+            connections,
+            acceptor,
+        })
+    }
+
+    /// The address the listener is bound to, e.g. to find the port chosen for port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Stops accepting connections, closes the open ones once their current row is applied,
+    /// and returns the engine.
+    pub fn shutdown(self) -> PaymentEngine {
+        self.stopping.store(true, Ordering::SeqCst);
+        // Wake the acceptor, which is blocked waiting for a connection.
+        let _ = TcpStream::connect(self.addr);
+        self.acceptor.join().expect("acceptor thread panicked");
+        let open: Vec<_> = self.connections.lock().expect("connections lock poisoned").drain().collect();
+        for (_, (stream, handle)) in open {
+            let _ = stream.shutdown(Shutdown::Both);
+            handle.join().expect("connection thread panicked");
+        }
+        // A connection thread that already finished may not have dropped its handle on the
+        // engine yet, so the engine is swapped out rather than unwrapped.
+        let mut engine = self.engine.lock().expect("engine lock poisoned");
+        std::mem::take(&mut *engine)
+    }
+}
+
+fn accept(listener: TcpListener, engine: Arc<Mutex<PaymentEngine>>, stopping: Arc<AtomicBool>, connections: Connections) {
+    for (id, stream) in (0..).zip(listener.incoming()) {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        // A failed accept only affects the connection that was being set up.
+        let Ok(stream) = stream else { continue };
+        let Ok(registered) = stream.try_clone() else { continue };
+        // Hold the lock until the handle is registered, so the thread cannot deregister first.
+        let mut open = connections.lock().expect("connections lock poisoned");
+        let handle = {
+            let (engine, connections) = (Arc::clone(&engine), Arc::clone(&connections));
+            thread::spawn(move || {
+                // The peer going away mid-row is not an error worth reporting.
+                let _ = serve(stream, &engine);
+                connections.lock().expect("connections lock poisoned").remove(&id);
+            })
+        };
+        open.insert(id, (registered, handle));
+    }
+}
+
+/// Applies the rows of one connection, replying to each.
+fn serve(stream: TcpStream, engine: &Mutex<PaymentEngine>) -> io::Result<()> {
+    let mut replies = BufWriter::new(stream.try_clone()?);
+    for row in RecordReader::csv_with_optional_header(stream)? {
+        let row = row?;
+        let reply = match row.record {
+            Ok(record) => {
+                let mut engine = engine.lock().expect("engine lock poisoned");
+                let replays = engine.replays();
+                match engine.process(record) {
+                    Ok(()) if engine.replays() > replays => format!("OK {} replayed", row.line),
+                    Ok(()) => format!("OK {}", row.line),
+                    Err(e) => format!("ERR {} {} {}", row.line, e.kind(), e),
+                }
+            }
+            Err(failure) => format!("ERR {} {} {}", row.line, PARSE_ERROR, failure.message.replace('\n', " ")),
+        };
+        writeln!(replies, "{reply}")?;
+        replies.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+    use std::io::{BufRead, BufReader};
+
+    /// Sends `input` on a new connection, closes the sending side and returns the replies.
+    fn send(addr: SocketAddr, input: &str) -> Vec<String> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(input.as_bytes()).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        BufReader::new(stream).lines().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_each_row_is_acknowledged() {
+        let listener = LineListener::start("127.0.0.1:0", PaymentEngine::new()).unwrap();
+        let replies = send(
+            listener.local_addr(),
+            "deposit,1,1,10.0\n# comment\nwithdrawal,1,2,20.0\nrefund,1,3\ndeposit,1,1,10.0\n",
+        );
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], "OK 1");
+        assert_eq!(replies[1], "ERR 3 InsufficientFunds Insufficient funds for client 1 to withdraw 20.0");
+        assert!(replies[2].starts_with("ERR 4 ParseError "));
+        assert_eq!(replies[3], "OK 5 replayed");
+        let engine = listener.shutdown();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(10.0));
+    }
+
+    #[test]
+    fn test_concurrent_connections_share_one_engine() {
+        let listener = LineListener::start("127.0.0.1:0", PaymentEngine::new()).unwrap();
+        let addr = listener.local_addr();
+        let senders: Vec<_> = (1..=8u16)
+            .map(|client| {
+                thread::spawn(move || {
+                    let rows: String = (0..50u32)
+                        .map(|i| format!("deposit,{client},{},1.0\n", u32::from(client) * 1000 + i))
+                        .collect();
+                    send(addr, &format!("type,client,tx,amount\n{rows}"))
+                })
+            })
+            .collect();
+        for sender in senders {
+            let replies = sender.join().unwrap();
+            assert_eq!(replies.len(), 50);
+            assert!(replies.iter().all(|reply| reply.starts_with("OK ")));
+        }
+        // A connection left open is closed by the shutdown.
+        let idle = TcpStream::connect(addr).unwrap();
+        let engine = listener.shutdown();
+        assert_eq!(engine.accounts().count(), 8);
+        assert!(engine.accounts().all(|(_, _, account)| account.available() == dec!(50.0)));
+        drop(idle);
+    }
+}
@@ -1,20 +1,38 @@
+use crate::config::RedisputePolicy;
 use crate::error::EngineError;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize, Serializer};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
 
 /// The type of transaction being processed.
-#[derive(Debug, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
+    /// Moves funds from the client to the `counterparty` client.
+    Transfer,
     Dispute,
     Resolve,
     Chargeback,
+    /// Admin: clears a chargeback lock and a freeze.
+    Unlock,
+    /// Admin: stops the account from moving funds while disputes and chargebacks still go through.
+    Freeze,
+    /// Admin: closes the account for good; every later row for it is rejected.
+    Close,
+}
+
+impl TransactionType {
+    /// Whether this is an administrative action rather than a money movement.
+    pub fn is_admin(self) -> bool {
+        matches!(self, TransactionType::Unlock | TransactionType::Freeze | TransactionType::Close)
+    }
 }
 
 /// A single record from the input CSV file.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct InputRecord {
     #[serde(rename = "type")]
     pub transaction_type: TransactionType,
@@ -23,17 +41,245 @@ pub struct InputRecord {
     #[serde(rename = "tx")]
     pub tx_id: u32,
     pub amount: Option<Decimal>,
+    /// The currency of a deposit or withdrawal. On dispute-family rows it is optional and,
+    /// when given, must match the disputed transaction.
+    #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+    pub currency: Currency,
+    /// The receiving client of a transfer; ignored on other rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<u16>,
+    /// When the row happened, in seconds since the Unix epoch. Only used for dispute windows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// The reason code of an admin action; ignored on other rows.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl InputRecord {
+    /// Creates a record in the unspecified currency.
+    pub fn new(transaction_type: TransactionType, client_id: u16, tx_id: u32, amount: Option<Decimal>) -> Self {
+        Self {
+            transaction_type,
+            client_id,
+            tx_id,
+            amount,
+            currency: Currency::default(),
+            counterparty: None,
+            timestamp: None,
+            reason: None,
+        }
+    }
+
+    /// A deposit of `amount` into the client's account.
+    pub fn deposit(client_id: u16, tx_id: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Deposit, client_id, tx_id, Some(amount))
+    }
+
+    /// A withdrawal of `amount` from the client's account.
+    pub fn withdrawal(client_id: u16, tx_id: u32, amount: Decimal) -> Self {
+        Self::new(TransactionType::Withdrawal, client_id, tx_id, Some(amount))
+    }
+
+    /// A transfer of `amount` from the client to the `counterparty` client.
+    pub fn transfer(client_id: u16, tx_id: u32, counterparty: u16, amount: Decimal) -> Self {
+        Self {
+            counterparty: Some(counterparty),
+            ..Self::new(TransactionType::Transfer, client_id, tx_id, Some(amount))
+        }
+    }
+
+    /// A claim that transaction `tx_id` was erroneous.
+    pub fn dispute(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TransactionType::Dispute, client_id, tx_id, None)
+    }
+
+    /// Settles the dispute on transaction `tx_id` in the client's favour.
+    pub fn resolve(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TransactionType::Resolve, client_id, tx_id, None)
+    }
+
+    /// Reverses the disputed transaction `tx_id`.
+    pub fn chargeback(client_id: u16, tx_id: u32) -> Self {
+        Self::new(TransactionType::Chargeback, client_id, tx_id, None)
+    }
+
+    /// An admin action of the given type on the client's accounts, with its reason code.
+    pub fn admin(action: TransactionType, client_id: u16, tx_id: u32, reason: &str) -> Self {
+        Self {
+            reason: Some(reason.to_string()),
+            ..Self::new(action, client_id, tx_id, None)
+        }
+    }
+
+    /// Sets the currency of the record.
+    pub fn in_currency(mut self, currency: Currency) -> Self {
+        self.currency = currency;
+        self
+    }
+
+    /// Sets the timestamp of the record, in seconds since the Unix epoch.
+    pub fn at(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// A hash of everything that defines the row, used to tell a replayed row from a reused ID.
+    /// It is FNV-1a over a canonical rendering, so it is stable across builds and can be persisted.
+    /// Amounts are normalized first, so `1.0` and `1.00` hash alike.
+    pub fn content_hash(&self) -> u64 {
+        let content = format!(
+            "{:?}|{}|{}|{}|{}|{:?}|{:?}",
+            self.transaction_type,
+            self.client_id,
+            self.tx_id,
+            self.amount.map(|amount| amount.normalize()).unwrap_or_default(),
+            self.currency,
+            self.counterparty,
+            self.timestamp,
+        );
+        content.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+}
+
+/// A three-letter currency code such as `EUR`, stored in upper case.
+///
+/// Input without a currency uses the unspecified currency, which is a currency of its own:
+/// it keeps single-currency input working unchanged and is never mixed with named currencies.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    /// Whether this is the currency of input rows that do not name one.
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    /// Parses a three-letter code, case-insensitively. An empty string is the unspecified currency.
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        if code.is_empty() {
+            return Ok(Self::default());
+        }
+        match <[u8; 3]>::try_from(code.as_bytes()) {
+            Ok(bytes) if bytes.iter().all(u8::is_ascii_alphabetic) => Ok(Self(bytes.map(|b| b.to_ascii_uppercase()))),
+            _ => Err(format!("invalid currency code '{}', expected three letters", code)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_unspecified() {
+            // Only ASCII letters are ever stored.
+            f.write_str(std::str::from_utf8(&self.0).unwrap_or_default())?;
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = Option::<String>::deserialize(deserializer)?.unwrap_or_default();
+        code.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 /// The state of a single client account. Fields are private to enforce state changes via methods.
-#[derive(Debug, Default, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Account {
     pub(crate) available: Decimal,
     pub(crate) held: Decimal,
     pub(crate) locked: bool,
+    pub(crate) status: AccountStatus,
+}
+
+/// Whether an account was frozen or closed by an admin action.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Only disputes, resolves and chargebacks are accepted.
+    Frozen,
+    /// Every row is rejected.
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn is_active(&self) -> bool {
+        *self == AccountStatus::Active
+    }
 }
 
 impl Account {
+    /// Funds available for trading, withdrawal, etc.
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    /// Funds held for dispute.
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    /// Whether the account is locked, which happens after a chargeback.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// How far 'available' is below zero, i.e. how much of an overdraft is in use.
+    pub fn credit_used(&self) -> Decimal {
+        (-self.available).max(Decimal::ZERO)
+    }
+
+    /// Whether the account was frozen or closed by an admin action.
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    /// Checks that the account may move funds: it must not be closed, frozen or locked.
+    pub fn ensure_active(&self, client_id: u16) -> Result<(), EngineError> {
+        match self.status {
+            AccountStatus::Closed => Err(EngineError::AccountClosed(client_id)),
+            AccountStatus::Frozen => Err(EngineError::AccountFrozen(client_id)),
+            AccountStatus::Active if self.locked => Err(EngineError::AccountLocked(client_id)),
+            AccountStatus::Active => Ok(()),
+        }
+    }
+
+    /// Clears a chargeback lock and a freeze.
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.status = AccountStatus::Active;
+    }
+
+    /// Locks the account, as a chargeback does.
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    /// Stops the account from moving funds.
+    pub fn freeze(&mut self) {
+        self.status = AccountStatus::Frozen;
+    }
+
+    /// Closes the account for good.
+    pub fn close(&mut self) {
+        self.status = AccountStatus::Closed;
+    }
+
     /// Deposits a given amount into the account.
     pub fn deposit(&mut self, amount: Decimal) {
         self.available += amount;
@@ -41,7 +287,12 @@ impl Account {
 
     /// Withdraws a given amount from the account.
     pub fn withdraw(&mut self, amount: Decimal) -> Result<(), EngineError> {
-        if self.available < amount {
+        self.withdraw_on_credit(amount, Decimal::ZERO)
+    }
+
+    /// Withdraws a given amount, letting 'available' go down to `-credit_limit`.
+    pub fn withdraw_on_credit(&mut self, amount: Decimal, credit_limit: Decimal) -> Result<(), EngineError> {
+        if self.available + credit_limit < amount {
             return Err(EngineError::InsufficientFunds(0, amount)); // Client ID filled by engine
         }
         self.available -= amount;
@@ -54,6 +305,11 @@ impl Account {
         self.held += amount;
     }
 
+    /// Takes a fee from 'available'. Like a dispute, a fee may leave 'available' negative.
+    pub fn charge_fee(&mut self, fee: Decimal) {
+        self.available -= fee;
+    }
+
     /// Moves funds from 'held' back to 'available' for a resolution.
     pub fn release_from_dispute(&mut self, amount: Decimal) {
         self.held -= amount;
@@ -66,6 +322,23 @@ impl Account {
         self.locked = true;
     }
 
+    /// Re-credits a disputed withdrawal into 'held' while the claim is investigated.
+    pub fn hold_withdrawal_for_dispute(&mut self, amount: Decimal) {
+        self.held += amount;
+    }
+
+    /// Drops the re-credited funds from 'held'; the original withdrawal stands.
+    pub fn release_withdrawal_from_dispute(&mut self, amount: Decimal) {
+        self.held -= amount;
+    }
+
+    /// Reverses a withdrawal by restoring the held funds to 'available' and locks the account.
+    pub fn chargeback_withdrawal(&mut self, amount: Decimal) {
+        self.held -= amount;
+        self.available += amount;
+        self.locked = true;
+    }
+
     /// Calculates the total funds in the account (available + held).
     pub fn total(&self) -> Decimal {
         self.available + self.held
@@ -73,7 +346,7 @@ impl Account {
 }
 
 /// A custom serialization function to format a Decimal to exactly four decimal places.
-fn serialize_with_four_decimals<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
+pub(crate) fn serialize_with_four_decimals<S>(value: &Decimal, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -83,19 +356,92 @@ where
     serializer.serialize_str(&formatted_value)
 }
 
-/// A record of a deposit transaction, stored for potential disputes.
-/// Optimized to not store client_id, as it's redundant.
+pub(crate) fn serialize_option_with_four_decimals<S>(value: &Option<Decimal>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(value) => serialize_with_four_decimals(value, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// A record of a deposit or withdrawal transaction, or of one leg of a transfer, stored for
+/// potential disputes. The owning client is kept so dispute-family rows can only act on their
+/// own transactions.
 #[derive(Debug, Clone, Copy)]
 pub struct TransactionRecord {
+    pub client_id: u16,
     pub amount: Decimal,
+    pub currency: Currency,
+    pub direction: TransactionDirection,
     pub status: TransactionStatus,
+    /// The part of the amount currently under dispute.
+    pub disputed: Decimal,
+    /// The part of the amount whose disputes were resolved.
+    pub resolved: Decimal,
+    /// The part of the amount that was charged back.
+    pub charged_back: Decimal,
+    /// For a transfer leg, the client on the other side.
+    pub counterparty: Option<u16>,
+    /// The input timestamp of the transaction, if it had one.
+    pub timestamp: Option<u64>,
+    /// The `content_hash` of the row that created the entry, if known.
+    pub content_hash: Option<u64>,
 }
 
-/// The status of a transaction, used to track the dispute lifecycle.
-#[derive(Debug, Clone, Copy, PartialEq)]
+impl TransactionRecord {
+    /// A new, undisputed ledger entry.
+    pub fn new(client_id: u16, amount: Decimal, currency: Currency, direction: TransactionDirection) -> Self {
+        Self {
+            client_id,
+            amount,
+            currency,
+            direction,
+            status: TransactionStatus::Normal,
+            disputed: Decimal::ZERO,
+            resolved: Decimal::ZERO,
+            charged_back: Decimal::ZERO,
+            counterparty: None,
+            timestamp: None,
+            content_hash: None,
+        }
+    }
+
+    /// The part of the amount that can still be disputed: not under dispute, not charged back
+    /// and, unless re-disputes are allowed, not already resolved.
+    pub fn disputable(&self, redispute: RedisputePolicy) -> Decimal {
+        let resolved = match redispute {
+            RedisputePolicy::Reject => self.resolved,
+            RedisputePolicy::Allow => Decimal::ZERO,
+        };
+        self.amount - self.disputed - self.charged_back - resolved
+    }
+}
+
+/// Whether a stored transaction credited or debited the account.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionDirection {
+    /// A deposit: disputing it holds funds that are already available.
+    Credit,
+    /// A withdrawal: disputing it re-credits the paid-out funds into held.
+    Debit,
+}
+
+/// The status of a transaction, used to track the dispute lifecycle:
+/// `Normal` -> `Disputed` -> `Resolved` | `ChargedBack`.
+/// A transaction stays `Disputed` until every disputed portion has been resolved or charged back;
+/// its status then follows the last resolve or chargeback.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionStatus {
     Normal,
     Disputed,
+    /// The dispute was settled in the client's favour. Re-disputing depends on `RedisputePolicy`.
+    Resolved,
+    /// Terminal: the transaction was reversed and cannot be disputed again.
+    ChargedBack,
 }
 
 /// A single record for the output CSV file.
@@ -103,11 +449,58 @@ pub enum TransactionStatus {
 pub struct OutputRecord {
     #[serde(rename = "client")]
     pub client_id: u16,
+    /// Only written with `EngineConfig::currencies`, so single-currency output is unchanged.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
     #[serde(serialize_with = "serialize_with_four_decimals")]
     pub available: Decimal,
     #[serde(serialize_with = "serialize_with_four_decimals")]
     pub held: Decimal,
     #[serde(serialize_with = "serialize_with_four_decimals")]
     pub total: Decimal,
+    /// Only written when credit limits are configured.
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_option_with_four_decimals")]
+    pub credit_used: Option<Decimal>,
     pub locked: bool,
-}
\ No newline at end of file
+    /// Only written with `EngineConfig::status_column`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<AccountStatus>,
+}
+
+/// An admin action applied to a client's accounts, kept as an audit trail.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminAction {
+    /// The transaction ID of the admin row; admin rows are not part of the transaction ledger.
+    pub tx: u32,
+    pub client: u16,
+    /// The currency the action was limited to; unspecified when it applied to every balance.
+    #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+    pub currency: Currency,
+    pub action: TransactionType,
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+impl fmt::Display for AdminAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} of client {}", self.action, self.client)?;
+        if !self.currency.is_unspecified() {
+            write!(f, " in {}", self.currency)?;
+        }
+        write!(f, " (tx {}, reason: {})", self.tx, self.reason)
+    }
+}
+
+/// The row order used when writing account states.
+/// Every order breaks ties by client ID and then currency, so the same engine state always produces identical output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputOrder {
+    /// Ascending client ID.
+    #[default]
+    ClientId,
+    /// Ascending total funds.
+    Total,
+    /// Locked accounts first.
+    Locked,
+}
@@ -0,0 +1,578 @@
+use crate::config::EngineConfig;
+use crate::engine::{PaymentEngine, Transfer};
+use crate::error::EngineError;
+use crate::events::EventSink;
+use crate::invariants::Probe;
+use crate::models::{AdminAction, Currency, InputRecord, TransactionType};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+
+/// Number of records buffered per worker before they are handed over as one message.
+const BATCH_SIZE: usize = 1024;
+/// Number of batches that may be queued per worker before the dispatcher blocks.
+const QUEUE_DEPTH: usize = 16;
+
+/// A rejected record: its submission sequence number, the caller's tag and the error.
+type Rejected<T> = (u64, T, EngineError);
+
+/// A message from the dispatcher to a worker thread.
+enum Message<T> {
+    /// Records to apply, each with its submission sequence number and the caller's tag.
+    Batch(Vec<(u64, T, InputRecord)>),
+    /// Asks which client owns a transaction ID in the worker's ledger, if any.
+    Owner(u32, Sender<Option<u16>>),
+    /// Asks whether a client's account in the given currency may be credited by a transfer.
+    Receivable(u16, Currency, Sender<Result<(), EngineError>>),
+    /// Applies the sending leg of a transfer, given whether the receiving account may be credited.
+    Debit(Transfer, Result<(), EngineError>, Sender<Result<(), EngineError>>),
+    /// Applies an admin action and returns it for the audit log.
+    Admin(InputRecord, Sender<Result<AdminAction, EngineError>>),
+    /// Applies the receiving leg of a transfer whose sending leg was accepted. With invariant
+    /// checks on, the outcome is replied, since a leg that broke an invariant was undone.
+    Credit(Transfer, Option<Sender<Result<(), EngineError>>>),
+    /// Tells the shard that applied the sending leg of a transfer, with invariant checks on,
+    /// whether the receiving leg stood. If not, the sending leg is undone as well.
+    Settle(bool),
+}
+
+/// What the dispatcher knows about which client a deposit/withdrawal ID belongs to.
+enum Claim {
+    /// A shard has confirmed that this client's record was accepted.
+    Confirmed(u16),
+    /// Clients that submitted the ID; none of them is known to have been accepted yet.
+    Candidates(Vec<u16>),
+}
+
+struct Worker<T> {
+    sender: SyncSender<Message<T>>,
+    pending: Vec<(u64, T, InputRecord)>,
+    handle: JoinHandle<(PaymentEngine, Vec<Rejected<T>>)>,
+}
+
+/// The merged result of a parallel run.
+pub struct ParallelOutcome<T> {
+    /// A single engine holding the state of every shard.
+    pub engine: PaymentEngine,
+    /// Rejected records as (tag, error), in submission order.
+    pub errors: Vec<(T, EngineError)>,
+}
+
+/// Processes records on several worker engines, sharded by client ID.
+///
+/// Account state is only ever touched per client, so each client is pinned to one worker and
+/// its records are applied in submission order. Transaction IDs are global, though: when a row
+/// refers to an ID already submitted by another client, the dispatcher asks the shards involved
+/// whether that ID was accepted, which reproduces the single-threaded duplicate and ownership
+/// checks exactly. Transfers may touch two shards, so they are applied synchronously: the
+/// receiving shard reports whether the counterparty may be credited, the sending shard applies
+/// the debit, and only then is the credit queued. Admin rows are applied synchronously as well,
+/// so the audit log keeps their submission order. The merged state is therefore identical to a
+/// `PaymentEngine` run.
+///
+/// With invariant checks on, each worker checks the records, transfer legs and admin actions
+/// it applies, as `PaymentEngine::process` does, and undoes any that broke an invariant. A
+/// transfer whose receiving leg was undone has its sending leg undone too, so it is rejected as
+/// a whole.
+///
+/// Each record travels with a caller-supplied tag of type `T` (for example its input line),
+/// which is handed back alongside the error if the record is rejected.
+pub struct ParallelEngine<T> {
+    workers: Vec<Worker<T>>,
+    claims: HashMap<u32, Claim>,
+    /// The receiving client of every accepted transfer, which may dispute its leg.
+    counterparties: HashMap<u32, u16>,
+    /// Admin actions applied during this run, in submission order.
+    admin_actions: Vec<AdminAction>,
+    errors: Vec<Rejected<T>>,
+    next_seq: u64,
+    /// The event sink of the engine the run started from, handed back by `finish`.
+    event_sink: Option<Box<dyn EventSink>>,
+    /// Whether rows may name a currency, checked here for the rows applied synchronously.
+    currencies: bool,
+    /// Whether the shards check invariants, in which case transfers wait for their credit.
+    invariant_checks: bool,
+}
+
+impl<T: Send + 'static> ParallelEngine<T> {
+    /// Spawns `threads` empty worker engines (at least one) configured with `config`.
+    pub fn new(threads: usize, config: EngineConfig) -> Self {
+        Self::from_engine(PaymentEngine::with_config(config), threads)
+    }
+
+    /// Spawns `threads` worker engines (at least one) that continue from the state of `engine`,
+    /// for example one restored from a snapshot.
+    ///
+    /// Shards do not emit domain events: the order of events across clients would depend on
+    /// thread scheduling. An event sink set on `engine` is put back on the merged engine.
+    pub fn from_engine(mut engine: PaymentEngine, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let event_sink = engine.take_event_sink();
+        let currencies = engine.config().currencies;
+        let invariant_checks = engine.invariant_checks();
+        let shards = engine.split(threads);
+        let mut claims = HashMap::new();
+        let mut counterparties = HashMap::new();
+        let workers = shards
+            .into_iter()
+            .map(|shard| {
+                claims.extend(
+                    shard
+                        .ledger()
+                        .map(|(tx_id, tx)| (tx_id, Claim::Confirmed(tx.client_id))),
+                );
+                counterparties.extend(shard.transfer_credits().map(|(tx_id, tx)| (tx_id, tx.client_id)));
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+                let handle = thread::spawn(move || run_worker(receiver, shard));
+                Worker {
+                    sender,
+                    pending: Vec::with_capacity(BATCH_SIZE),
+                    handle,
+                }
+            })
+            .collect();
+        Self {
+            workers,
+            claims,
+            counterparties,
+            admin_actions: Vec::new(),
+            errors: Vec::new(),
+            next_seq: 0,
+            event_sink,
+            currencies,
+            invariant_checks,
+        }
+    }
+
+    /// Submits a record. Rejections are collected and reported, with their tag, by `finish`.
+    pub fn process(&mut self, tag: T, record: InputRecord) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        // Transaction IDs are global, so rows touching an ID owned by another shard's client
+        // are rejected here, exactly as a single engine would reject them.
+        let rejection = match record.transaction_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let foreign = self.foreign_owner(record.tx_id, record.client_id).is_some();
+                if !foreign {
+                    self.add_candidate(record.tx_id, record.client_id);
+                }
+                foreign.then_some(EngineError::DuplicateTransactionId(record.tx_id))
+            }
+            TransactionType::Transfer | TransactionType::Unlock | TransactionType::Freeze | TransactionType::Close
+                if !self.currencies && !record.currency.is_unspecified() =>
+            {
+                Some(EngineError::CurrenciesDisabled(record.tx_id))
+            }
+            TransactionType::Transfer => self.transfer(&record).err(),
+            TransactionType::Unlock | TransactionType::Freeze | TransactionType::Close => self.admin(&record).err(),
+            _ => self
+                .foreign_owner(record.tx_id, record.client_id)
+                .filter(|_| self.counterparties.get(&record.tx_id) != Some(&record.client_id))
+                .map(|_| EngineError::ClientMismatch(record.tx_id, record.client_id)),
+        };
+        if let Some(e) = rejection {
+            self.errors.push((seq, tag, e));
+            return;
+        }
+        if record.transaction_type == TransactionType::Transfer || record.transaction_type.is_admin() {
+            // Accepted transfers and admin actions have already been applied by their shards.
+            return;
+        }
+
+        let shard = self.shard(record.client_id);
+        let worker = &mut self.workers[shard];
+        worker.pending.push((seq, tag, record));
+        if worker.pending.len() >= BATCH_SIZE {
+            Self::flush(worker);
+        }
+    }
+
+    /// Waits for every worker to drain its queue and merges the shards into one engine.
+    pub fn finish(mut self) -> ParallelOutcome<T> {
+        let mut engine: Option<PaymentEngine> = None;
+        let mut errors = std::mem::take(&mut self.errors);
+        for mut worker in self.workers {
+            Self::flush(&mut worker);
+            drop(worker.sender);
+            let (shard, shard_errors) = worker.handle.join().expect("worker thread panicked");
+            errors.extend(shard_errors);
+            match engine.as_mut() {
+                Some(engine) => engine.absorb(shard),
+                None => engine = Some(shard),
+            }
+        }
+        let mut engine = engine.expect("at least one worker");
+        engine.log_admin_actions(self.admin_actions);
+        if let Some(sink) = self.event_sink {
+            engine.set_event_sink(sink);
+        }
+        errors.sort_by_key(|(seq, _, _)| *seq);
+        ParallelOutcome {
+            engine,
+            errors: errors.into_iter().map(|(_, tag, e)| (tag, e)).collect(),
+        }
+    }
+
+    fn shard(&self, client_id: u16) -> usize {
+        client_id as usize % self.workers.len()
+    }
+
+    fn flush(worker: &mut Worker<T>) {
+        if worker.pending.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut worker.pending, Vec::with_capacity(BATCH_SIZE));
+        worker
+            .sender
+            .send(Message::Batch(batch))
+            .expect("worker thread stopped");
+    }
+
+    /// Returns the owner of a transaction ID if it was accepted for a client other than `client_id`.
+    fn foreign_owner(&mut self, tx_id: u32, client_id: u16) -> Option<u16> {
+        let candidates = match self.claims.get(&tx_id) {
+            None => return None,
+            Some(Claim::Confirmed(owner)) => return (*owner != client_id).then_some(*owner),
+            Some(Claim::Candidates(candidates)) if candidates.iter().all(|c| *c == client_id) => {
+                return None
+            }
+            Some(Claim::Candidates(candidates)) => candidates.clone(),
+        };
+
+        // The ID is contested: ask the shards of earlier submitters whether one was accepted.
+        // Channels are FIFO, so the answer reflects every record submitted before this one.
+        let mut shards: Vec<usize> = candidates.iter().map(|c| self.shard(*c)).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        for shard in shards {
+            if let Some(owner) = self.query_owner(shard, tx_id) {
+                self.claims.insert(tx_id, Claim::Confirmed(owner));
+                return (owner != client_id).then_some(owner);
+            }
+        }
+        None
+    }
+
+    /// Records that `client_id` submitted a deposit or withdrawal with this ID.
+    fn add_candidate(&mut self, tx_id: u32, client_id: u16) {
+        match self.claims.get_mut(&tx_id) {
+            None => {
+                self.claims.insert(tx_id, Claim::Candidates(vec![client_id]));
+            }
+            Some(Claim::Candidates(candidates)) if !candidates.contains(&client_id) => {
+                candidates.push(client_id)
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Applies a transfer across the shards of its two clients, waiting for the outcome.
+    fn transfer(&mut self, record: &InputRecord) -> Result<(), EngineError> {
+        let transfer = PaymentEngine::validate_transfer(record)?;
+        if self.foreign_owner(transfer.tx_id, transfer.from).is_some() {
+            return Err(EngineError::DuplicateTransactionId(transfer.tx_id));
+        }
+        let receiving = self.shard(transfer.to);
+        let receivable = self.request(receiving, |reply| Message::Receivable(transfer.to, transfer.currency, reply));
+        let sending = self.shard(transfer.from);
+        self.request(sending, |reply| Message::Debit(transfer, receivable, reply))?;
+        if self.invariant_checks {
+            let credited = self.request(receiving, |reply| Message::Credit(transfer, Some(reply)));
+            self.send(sending, Message::Settle(credited.is_ok()));
+            credited?;
+        } else {
+            self.send(receiving, Message::Credit(transfer, None));
+        }
+        self.claims.insert(transfer.tx_id, Claim::Confirmed(transfer.from));
+        self.counterparties.insert(transfer.tx_id, transfer.to);
+        Ok(())
+    }
+
+    /// Applies an admin action on its client's shard, waiting for the outcome.
+    fn admin(&mut self, record: &InputRecord) -> Result<(), EngineError> {
+        let shard = self.shard(record.client_id);
+        let action = self.request(shard, |reply| Message::Admin(record.clone(), reply))?;
+        self.admin_actions.push(action);
+        Ok(())
+    }
+
+    fn query_owner(&mut self, shard: usize, tx_id: u32) -> Option<u16> {
+        self.request(shard, |reply| Message::Owner(tx_id, reply))
+    }
+
+    /// Sends a message to a shard after its pending records and waits for the reply.
+    fn request<R>(&mut self, shard: usize, message: impl FnOnce(Sender<R>) -> Message<T>) -> R {
+        let (reply, answer) = mpsc::channel();
+        self.send(shard, message(reply));
+        answer.recv().expect("worker thread stopped")
+    }
+
+    /// Sends a message to a shard after its pending records.
+    fn send(&mut self, shard: usize, message: Message<T>) {
+        let worker = &mut self.workers[shard];
+        Self::flush(worker);
+        worker.sender.send(message).expect("worker thread stopped");
+    }
+}
+
+fn run_worker<T>(
+    receiver: Receiver<Message<T>>,
+    mut engine: PaymentEngine,
+) -> (PaymentEngine, Vec<Rejected<T>>) {
+    let mut errors = Vec::new();
+    // The sending leg of the transfer in progress, kept until its receiving leg is known to stand.
+    let mut unsettled: Option<Probe> = None;
+    for message in receiver {
+        match message {
+            Message::Batch(batch) => {
+                for (seq, tag, record) in batch {
+                    if let Err(e) = engine.process(record) {
+                        errors.push((seq, tag, e));
+                    }
+                }
+            }
+            Message::Owner(tx_id, reply) => {
+                let owner = engine.transaction(tx_id).map(|tx| tx.client_id);
+                // The dispatcher may have given up waiting; nothing to do then.
+                let _ = reply.send(owner);
+            }
+            Message::Receivable(client_id, currency, reply) => {
+                let _ = reply.send(engine.ensure_receivable(client_id, currency));
+            }
+            Message::Debit(transfer, receivable, reply) => {
+                let probe = engine.invariant_checks().then(|| Probe::capture_transfer(&engine, &transfer));
+                let mut result = engine.debit_transfer(&transfer, receivable);
+                match probe.map(|probe| probe.check(&mut engine)) {
+                    Some(Ok(probe)) if result.is_ok() => unsettled = Some(probe),
+                    Some(Err(violation)) => result = Err(EngineError::InvariantViolated(violation)),
+                    _ => {}
+                }
+                let _ = reply.send(result);
+            }
+            Message::Admin(record, reply) => {
+                let probe = engine.invariant_checks().then(|| Probe::capture(&engine, &record));
+                let mut result = engine.apply_admin(&record);
+                if let Some(Err(violation)) = probe.map(|probe| probe.check(&mut engine)) {
+                    result = Err(EngineError::InvariantViolated(violation));
+                }
+                let _ = reply.send(result);
+            }
+            Message::Credit(transfer, reply) => {
+                let probe = engine.invariant_checks().then(|| Probe::capture_transfer(&engine, &transfer));
+                engine.credit_transfer(&transfer);
+                let result = match probe.map(|probe| probe.check(&mut engine)) {
+                    Some(Err(violation)) => Err(EngineError::InvariantViolated(violation)),
+                    _ => Ok(()),
+                };
+                if let Some(reply) = reply {
+                    let _ = reply.send(result);
+                }
+            }
+            Message::Settle(credited) => {
+                if let Some(probe) = unsettled.take().filter(|_| !credited) {
+                    probe.roll_back(&mut engine);
+                }
+            }
+        }
+    }
+    (engine, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::Fee;
+    use rust_decimal::Decimal;
+
+    fn output(engine: &PaymentEngine) -> String {
+        let mut wtr = csv::Writer::from_writer(vec![]);
+        engine.write_output(&mut wtr).unwrap();
+        String::from_utf8(wtr.into_inner().unwrap()).unwrap()
+    }
+
+    /// A deterministic mix of every record type, with tx IDs deliberately reused across clients.
+    fn records() -> Vec<InputRecord> {
+        let mut state: u64 = 42;
+        let mut next = move |bound: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % bound
+        };
+        (0..20_000)
+            .map(|_| {
+                let transaction_type = match next(11) {
+                    0..=3 => TransactionType::Deposit,
+                    4..=5 => TransactionType::Withdrawal,
+                    6..=7 => TransactionType::Dispute,
+                    8 => TransactionType::Resolve,
+                    9 => TransactionType::Chargeback,
+                    _ => TransactionType::Transfer,
+                };
+                let amount = match transaction_type {
+                    TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Transfer => {
+                        Some(Decimal::new(next(100_000) as i64 + 1, 2))
+                    }
+                    _ => None,
+                };
+                let mut record = InputRecord::new(transaction_type, next(50) as u16, next(5_000) as u32, amount);
+                if transaction_type == TransactionType::Transfer {
+                    record.counterparty = Some(next(50) as u16);
+                }
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_output_matches_single_threaded() {
+        let mut single = PaymentEngine::new();
+        let mut rejected = Vec::new();
+        for (seq, record) in records().into_iter().enumerate() {
+            if let Err(e) = single.process(record) {
+                rejected.push((seq as u64, e));
+            }
+        }
+
+        for threads in [1, 3, 8] {
+            let mut parallel = ParallelEngine::new(threads, EngineConfig::default());
+            for (seq, record) in records().into_iter().enumerate() {
+                parallel.process(seq as u64, record);
+            }
+            let outcome = parallel.finish();
+            assert_eq!(output(&outcome.engine), output(&single), "threads = {threads}");
+            assert_eq!(outcome.errors, rejected, "threads = {threads}");
+        }
+    }
+
+    #[test]
+    fn test_parallel_run_continues_from_engine_state() {
+        let all = records();
+        let (first, second) = all.split_at(all.len() / 2);
+
+        let mut single = PaymentEngine::new();
+        for record in records() {
+            let _ = single.process(record);
+        }
+
+        let mut start = PaymentEngine::new();
+        for record in first.iter() {
+            let _ = start.process(record.clone());
+        }
+        let mut parallel = ParallelEngine::from_engine(start, 4);
+        for (seq, record) in second.iter().enumerate() {
+            parallel.process(seq, record.clone());
+        }
+        assert_eq!(output(&parallel.finish().engine), output(&single));
+    }
+
+    #[test]
+    fn test_cross_shard_duplicate_id_is_rejected() {
+        let mut parallel = ParallelEngine::new(2, EngineConfig::default());
+        parallel.process(0, InputRecord::deposit(1, 1, Decimal::new(100, 0)));
+        parallel.process(1, InputRecord::deposit(2, 1, Decimal::new(50, 0)));
+        let outcome = parallel.finish();
+        assert_eq!(outcome.errors, vec![(1, EngineError::DuplicateTransactionId(1))]);
+        assert!(outcome.engine.account(2).is_none());
+    }
+
+    #[test]
+    fn test_failed_withdrawal_does_not_claim_id() {
+        let mut parallel = ParallelEngine::new(2, EngineConfig::default());
+        parallel.process(0, InputRecord::deposit(1, 1, Decimal::new(10, 0)));
+        parallel.process(1, InputRecord::withdrawal(1, 2, Decimal::new(50, 0)));
+        parallel.process(2, InputRecord::deposit(2, 2, Decimal::new(50, 0)));
+        let outcome = parallel.finish();
+        assert_eq!(outcome.errors, vec![(1, EngineError::InsufficientFunds(1, Decimal::new(50, 0)))]);
+        assert_eq!(outcome.engine.account(2).unwrap().available(), Decimal::new(50, 0));
+    }
+
+    #[test]
+    fn test_rows_naming_a_currency_need_currencies_enabled() {
+        let eur: Currency = "EUR".parse().unwrap();
+        let mut parallel = ParallelEngine::new(2, EngineConfig::default());
+        parallel.process(0, InputRecord::deposit(1, 1, Decimal::new(10, 0)));
+        parallel.process(1, InputRecord::transfer(1, 2, 2, Decimal::new(5, 0)).in_currency(eur));
+        parallel.process(2, InputRecord::admin(TransactionType::Freeze, 1, 3, "aml").in_currency(eur));
+        let outcome = parallel.finish();
+        assert_eq!(
+            outcome.errors,
+            vec![(1, EngineError::CurrenciesDisabled(2)), (2, EngineError::CurrenciesDisabled(3))]
+        );
+        assert!(outcome.engine.admin_actions().is_empty());
+    }
+
+    #[test]
+    fn test_transfers_and_admin_rows_breaking_an_invariant_are_undone() {
+        let mut engine = PaymentEngine::new();
+        engine.process(InputRecord::deposit(1, 1, Decimal::new(10, 0))).unwrap();
+        engine.process(InputRecord::deposit(2, 2, Decimal::new(10, 0))).unwrap();
+        let mut snapshot = engine.snapshot();
+        // Every row touching client 2 now breaks an invariant.
+        snapshot.accounts.iter_mut().find(|a| a.client == 2).unwrap().held = Decimal::new(-1, 0);
+        let mut engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        engine.set_invariant_checks(true);
+        let mut parallel = ParallelEngine::from_engine(engine, 2);
+        parallel.process(0, InputRecord::transfer(1, 3, 2, Decimal::new(4, 0)));
+        parallel.process(1, InputRecord::admin(TransactionType::Freeze, 2, 4, "aml"));
+        parallel.process(2, InputRecord::transfer(1, 5, 4, Decimal::new(4, 0)));
+        let outcome = parallel.finish();
+        let errors: Vec<(i32, &str)> = outcome.errors.iter().map(|(tag, e)| (*tag, e.kind())).collect();
+        assert_eq!(errors, [(0, "InvariantViolated"), (1, "InvariantViolated")]);
+        // The sending leg of the first transfer was undone with its receiving leg.
+        assert_eq!(outcome.engine.account(1).unwrap().available(), Decimal::new(6, 0));
+        assert!(outcome.engine.transaction(3).is_none() && outcome.engine.transfer_credit(3).is_none());
+        assert_eq!(outcome.engine.account(4).unwrap().available(), Decimal::new(4, 0));
+        assert!(outcome.engine.admin_actions().is_empty());
+    }
+
+    #[test]
+    fn test_cross_shard_transfer_legs_can_be_disputed() {
+        let mut parallel = ParallelEngine::new(2, EngineConfig::default());
+        parallel.process(0, InputRecord::deposit(1, 1, Decimal::new(10, 0)));
+        parallel.process(1, InputRecord::transfer(1, 2, 2, Decimal::new(4, 0)));
+        parallel.process(2, InputRecord::dispute(2, 2));
+        parallel.process(3, InputRecord::dispute(3, 2));
+        parallel.process(4, InputRecord::chargeback(2, 2));
+        let outcome = parallel.finish();
+        assert_eq!(outcome.errors, vec![(3, EngineError::ClientMismatch(2, 3))]);
+        assert_eq!(outcome.engine.account(1).unwrap().available(), Decimal::new(6, 0));
+        let receiver = outcome.engine.account(2).unwrap();
+        assert_eq!(receiver.total(), Decimal::ZERO);
+        assert!(receiver.locked());
+    }
+
+    #[test]
+    fn test_admin_actions_keep_submission_order() {
+        let mut parallel = ParallelEngine::new(3, EngineConfig::default());
+        for client in 1..=6 {
+            parallel.process(client as u64, InputRecord::deposit(client, client as u32, Decimal::new(10, 0)));
+        }
+        for client in (1..=6).rev() {
+            parallel.process(10 + client as u64, InputRecord::admin(TransactionType::Freeze, client, 100 + client as u32, "aml"));
+        }
+        parallel.process(20, InputRecord::deposit(4, 20, Decimal::new(10, 0)));
+        let outcome = parallel.finish();
+        assert_eq!(outcome.errors, vec![(20, EngineError::AccountFrozen(4))]);
+        let clients: Vec<u16> = outcome.engine.admin_actions().iter().map(|a| a.client).collect();
+        assert_eq!(clients, [6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn test_fee_revenue_is_summed_across_shards() {
+        let mut config = EngineConfig::default();
+        let flat = Fee { percent: Decimal::ZERO, flat: Decimal::ONE };
+        config.fees.set_default(TransactionType::Withdrawal, flat);
+        config.fees.set_default(TransactionType::Transfer, flat);
+        let mut parallel = ParallelEngine::new(3, config);
+        for client in 1..=6 {
+            parallel.process(client as u64, InputRecord::deposit(client, client as u32, Decimal::new(10, 0)));
+            parallel.process(10 + client as u64, InputRecord::withdrawal(client, 10 + client as u32, Decimal::new(2, 0)));
+        }
+        parallel.process(20, InputRecord::transfer(1, 20, 2, Decimal::new(3, 0)));
+        let outcome = parallel.finish();
+        assert!(outcome.errors.is_empty());
+        assert_eq!(outcome.engine.account(1).unwrap().available(), Decimal::new(3, 0));
+        assert_eq!(outcome.engine.account(2).unwrap().available(), Decimal::new(10, 0));
+        assert_eq!(outcome.engine.fee_revenue(), vec![(Currency::default(), Decimal::new(7, 0))]);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::engine::PaymentEngine;
+use crate::error::ReconcileError;
+use crate::format::DataFormat;
+use crate::models::Currency;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// The fields of an expected-accounts file, named as in the account output.
+pub const EXPECTED_FIELDS: [&str; 6] = ["client", "currency", "available", "held", "total", "locked"];
+
+/// One account as a partner expects it. Only the fields present in the file are compared.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ExpectedAccount {
+    pub client: u16,
+    #[serde(default)]
+    pub currency: Currency,
+    pub available: Option<Decimal>,
+    pub held: Option<Decimal>,
+    pub total: Option<Decimal>,
+    pub locked: Option<bool>,
+}
+
+/// The account states a partner expects, keyed by client and currency.
+#[derive(Debug, Clone, Default)]
+pub struct ExpectedAccounts {
+    accounts: BTreeMap<(u16, Currency), ExpectedAccount>,
+}
+
+impl ExpectedAccounts {
+    /// Adds an expected account. Each client and currency may only be listed once.
+    pub fn insert(&mut self, account: ExpectedAccount) -> Result<(), ReconcileError> {
+        let key = (account.client, account.currency);
+        if self.accounts.insert(key, account).is_some() {
+            return Err(ReconcileError::DuplicateAccount(key.0, key.1));
+        }
+        Ok(())
+    }
+
+    /// Reads expected accounts in the account output format, as CSV or JSON Lines.
+    ///
+    /// `columns` maps fields to the names a partner's file uses instead, e.g. `("total", "Balance")`;
+    /// other columns are ignored. Amounts may have any number of decimal places.
+    pub fn from_reader<R: Read>(
+        reader: R,
+        format: DataFormat,
+        columns: &[(String, String)],
+    ) -> Result<Self, ReconcileError> {
+        if let Some((field, _)) = columns.iter().find(|(field, _)| !EXPECTED_FIELDS.contains(&field.as_str())) {
+            return Err(ReconcileError::UnknownField(field.clone()));
+        }
+        let field_of = |name: &str| {
+            columns
+                .iter()
+                .find(|(_, column)| column == name)
+                .map_or_else(|| name.to_string(), |(field, _)| field.clone())
+        };
+        let mut expected = Self::default();
+        match format {
+            DataFormat::Csv => {
+                let mut rdr = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(reader);
+                let headers: csv::StringRecord = rdr.headers()?.iter().map(field_of).collect();
+                rdr.set_headers(headers);
+                for row in rdr.deserialize() {
+                    expected.insert(row?)?;
+                }
+            }
+            DataFormat::JsonLines => {
+                for line in BufReader::new(reader).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let Value::Object(object) = serde_json::from_str(&line)? else {
+                        return Err(ReconcileError::NotAnObject(line));
+                    };
+                    let object = object.into_iter().map(|(key, value)| (field_of(&key), value)).collect();
+                    expected.insert(serde_json::from_value(Value::Object(object))?)?;
+                }
+            }
+        }
+        Ok(expected)
+    }
+
+    /// Loads expected accounts from a file, picking the format from its extension.
+    pub fn load(path: &Path, columns: &[(String, String)]) -> Result<Self, ReconcileError> {
+        Self::from_reader(File::open(path)?, DataFormat::from_path(path), columns)
+    }
+}
+
+/// The kind of difference between the engine and the expected accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Issue {
+    /// An expected account the engine does not have.
+    MissingClient,
+    /// An account the engine has but that was not expected.
+    ExtraClient,
+    /// A balance that differs by more than the tolerance.
+    BalanceMismatch,
+    /// An account whose locked flag differs from the expected one.
+    LockedMismatch,
+}
+
+/// One difference found by `reconcile`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub client: u16,
+    /// Only written with currencies enabled, as in the account output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub issue: Issue,
+    /// The field that differs; empty for missing and extra clients.
+    pub field: Option<&'static str>,
+    /// The value as given in the expected file.
+    pub expected: Option<String>,
+    /// The engine's value, formatted as in the account output.
+    pub actual: Option<String>,
+    /// `actual - expected` for a balance mismatch, unrounded so that differences below the
+    /// output's four decimal places still show. Empty for every other issue, including a locked
+    /// mismatch.
+    pub difference: Option<Decimal>,
+}
+
+/// Compares every account of the engine with the expected accounts. Balances count as equal
+/// when they differ by at most `tolerance`. Discrepancies are ordered by client and currency.
+pub fn reconcile(engine: &PaymentEngine, expected: &ExpectedAccounts, tolerance: Decimal) -> Vec<Discrepancy> {
+    let keys: BTreeSet<(u16, Currency)> = engine
+        .accounts()
+        .map(|(client, currency, _)| (client, currency))
+        .chain(expected.accounts.keys().copied())
+        .collect();
+    let multi_currency = engine.config().currencies;
+    let mut discrepancies = Vec::new();
+    for (client, currency) in keys {
+        let discrepancy = |issue, field, expected, actual, difference| Discrepancy {
+            client,
+            currency: multi_currency.then_some(currency),
+            issue,
+            field,
+            expected,
+            actual,
+            difference,
+        };
+        let account = engine.account_in(client, currency);
+        let Some(wanted) = expected.accounts.get(&(client, currency)) else {
+            discrepancies.push(discrepancy(Issue::ExtraClient, None, None, None, None));
+            continue;
+        };
+        let Some(account) = account else {
+            discrepancies.push(discrepancy(Issue::MissingClient, None, None, None, None));
+            continue;
+        };
+        let balances = [
+            ("available", wanted.available, account.available()),
+            ("held", wanted.held, account.held()),
+            ("total", wanted.total, account.total()),
+        ];
+        for (field, wanted, actual) in balances {
+            if let Some(wanted) = wanted {
+                let difference = actual - wanted;
+                if difference.abs() > tolerance {
+                    discrepancies.push(discrepancy(
+                        Issue::BalanceMismatch,
+                        Some(field),
+                        Some(wanted.to_string()),
+                        Some(format!("{:.4}", actual.round_dp(4))),
+                        Some(difference),
+                    ));
+                }
+            }
+        }
+        if let Some(wanted) = wanted.locked.filter(|locked| *locked != account.locked()) {
+            discrepancies.push(discrepancy(
+                Issue::LockedMismatch,
+                Some("locked"),
+                Some(wanted.to_string()),
+                Some(account.locked().to_string()),
+                None,
+            ));
+        }
+    }
+    discrepancies
+}
+
+/// Writes discrepancies as CSV or JSON Lines.
+pub fn write_discrepancies<W: Write>(discrepancies: &[Discrepancy], writer: W, format: DataFormat) -> io::Result<()> {
+    match format {
+        DataFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for discrepancy in discrepancies {
+                wtr.serialize(discrepancy)?;
+            }
+            wtr.flush()
+        }
+        DataFormat::JsonLines => {
+            let mut writer = writer;
+            for discrepancy in discrepancies {
+                serde_json::to_writer(&mut writer, discrepancy)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{InputRecord, TransactionType};
+    use rust_decimal_macros::dec;
+
+    fn engine() -> PaymentEngine {
+        let mut engine = PaymentEngine::new();
+        for record in [
+            InputRecord::deposit(1, 1, dec!(10.0)),
+            InputRecord::deposit(2, 2, dec!(5.0)),
+            InputRecord::dispute(2, 2),
+            InputRecord::chargeback(2, 2),
+            InputRecord::deposit(3, 3, dec!(1.0)),
+            InputRecord::admin(TransactionType::Freeze, 3, 4, "kyc"),
+        ] {
+            engine.process(record).unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn test_reconcile_reports_each_kind_of_difference() {
+        let expected = ExpectedAccounts::from_reader(
+            "client,available,held,total,locked\n\
+             1,10.00005,0,10.0002,false\n\
+             2,5,0,5,false\n\
+             4,1,0,1,false\n"
+                .as_bytes(),
+            DataFormat::Csv,
+            &[],
+        )
+        .unwrap();
+        let mut report = Vec::new();
+        write_discrepancies(&reconcile(&engine(), &expected, dec!(0.0001)), &mut report, DataFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "client,issue,field,expected,actual,difference\n\
+             1,balance_mismatch,total,10.0002,10.0000,-0.0002\n\
+             2,balance_mismatch,available,5,0.0000,-5\n\
+             2,balance_mismatch,total,5,0.0000,-5\n\
+             2,locked_mismatch,locked,false,true,\n\
+             3,extra_client,,,,\n\
+             4,missing_client,,,,\n"
+        );
+    }
+
+    #[test]
+    fn test_mapped_columns_and_partial_fields() {
+        let columns = [("client".to_string(), "Account".to_string()), ("total".to_string(), "Balance".to_string())];
+        let expected = ExpectedAccounts::from_reader(
+            "{\"Account\":1,\"Balance\":\"10\",\"Branch\":\"x\"}\n\n{\"Account\":2,\"Balance\":\"0\"}\n{\"Account\":3,\"Balance\":\"1.0\"}\n"
+                .as_bytes(),
+            DataFormat::JsonLines,
+            &columns,
+        )
+        .unwrap();
+        assert!(reconcile(&engine(), &expected, Decimal::ZERO).is_empty());
+
+        let unknown = [("balance".to_string(), "Balance".to_string())];
+        let result = ExpectedAccounts::from_reader("".as_bytes(), DataFormat::Csv, &unknown);
+        assert!(matches!(result, Err(ReconcileError::UnknownField(field)) if field == "balance"));
+        let result = ExpectedAccounts::from_reader("client,total\n1,1\n1,2\n".as_bytes(), DataFormat::Csv, &[]);
+        assert!(matches!(result, Err(ReconcileError::DuplicateAccount(1, _))));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::error::EngineError;
+use crate::format::DataFormat;
+use crate::input::ParseFailure;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// The error code reported for rows that could not be parsed into an `InputRecord`.
+pub const PARSE_ERROR: &str = "ParseError";
+
+/// One rejected input row, with enough context for a partner to find and fix it.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Rejection {
+    /// The line of the input file the row was read from.
+    pub line: u64,
+    /// The row as it appeared in the input.
+    pub raw: String,
+    /// The client ID, when it could be read.
+    pub client: Option<u16>,
+    /// The transaction ID, when it could be read.
+    pub tx: Option<u32>,
+    /// The `EngineError` variant name, or `ParseError`.
+    pub error: String,
+    /// The human-readable message.
+    pub message: String,
+}
+
+impl Rejection {
+    /// Describes a row the engine rejected.
+    pub fn from_engine_error(line: u64, raw: String, client: u16, tx: u32, e: &EngineError) -> Self {
+        Self {
+            line,
+            raw,
+            client: Some(client),
+            tx: Some(tx),
+            error: e.kind().to_string(),
+            message: e.to_string(),
+        }
+    }
+
+    /// Describes a row that could not be parsed.
+    pub fn from_parse_failure(line: u64, raw: String, failure: ParseFailure) -> Self {
+        Self {
+            line,
+            raw,
+            client: failure.client,
+            tx: failure.tx,
+            error: PARSE_ERROR.to_string(),
+            message: failure.message,
+        }
+    }
+}
+
+/// Streams rejections to a CSV or JSON Lines report.
+pub enum RejectionWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl<W: Write> RejectionWriter<W> {
+    pub fn new(writer: W, format: DataFormat) -> Self {
+        match format {
+            DataFormat::Csv => RejectionWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+            DataFormat::JsonLines => RejectionWriter::JsonLines(writer),
+        }
+    }
+
+    /// Appends one rejection to the report.
+    pub fn write(&mut self, rejection: &Rejection) -> io::Result<()> {
+        match self {
+            RejectionWriter::Csv(wtr) => wtr.serialize(rejection)?,
+            RejectionWriter::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, rejection)?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RejectionWriter::Csv(wtr) => wtr.flush(),
+            RejectionWriter::JsonLines(writer) => writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rejections() -> Vec<Rejection> {
+        vec![
+            Rejection::from_engine_error(3, "withdrawal,1,2,50".to_string(), 1, 2, &EngineError::InsufficientFunds(1, rust_decimal_macros::dec!(50))),
+            Rejection { line: 4, raw: "deposit,x,3,1".to_string(), client: None, tx: None, error: PARSE_ERROR.to_string(), message: "invalid digit".to_string() },
+        ]
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let mut writer = RejectionWriter::new(vec![], DataFormat::Csv);
+        for rejection in rejections() {
+            writer.write(&rejection).unwrap();
+        }
+        writer.flush().unwrap();
+        let RejectionWriter::Csv(wtr) = writer else { unreachable!() };
+        assert_eq!(
+            String::from_utf8((*wtr).into_inner().unwrap()).unwrap(),
+            "line,raw,client,tx,error,message\n\
+             3,\"withdrawal,1,2,50\",1,2,InsufficientFunds,Insufficient funds for client 1 to withdraw 50\n\
+             4,\"deposit,x,3,1\",,,ParseError,invalid digit\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines_report() {
+        let mut writer = RejectionWriter::new(vec![], DataFormat::JsonLines);
+        for rejection in rejections() {
+            writer.write(&rejection).unwrap();
+        }
+        let RejectionWriter::JsonLines(bytes) = writer else { unreachable!() };
+        let lines: Vec<serde_json::Value> = String::from_utf8(bytes).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines[0]["error"], "InsufficientFunds");
+        assert_eq!(lines[0]["line"], 3);
+        assert_eq!(lines[1]["client"], serde_json::Value::Null);
+        assert_eq!(lines[1]["raw"], "deposit,x,3,1");
+    }
+}
@@ -0,0 +1,363 @@
+use crate::engine::PaymentEngine;
+use crate::error::EngineError;
+use crate::input::{parse_json_value, ParseFailure};
+use crate::models::{OutputOrder, OutputRecord};
+use crate::rejections::PARSE_ERROR;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use tiny_http::{Header, Method, Request, Response};
+
+/// The body of every error response.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct ApiError {
+    /// The `EngineError` variant name, `ParseError` for a malformed record, or an HTTP-level
+    /// code such as `NotFound`.
+    pub error: String,
+    /// The human-readable message.
+    pub message: String,
+}
+
+impl ApiError {
+    fn new(error: &str, message: impl Into<String>) -> Self {
+        Self {
+            error: error.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<&EngineError> for ApiError {
+    fn from(e: &EngineError) -> Self {
+        Self::new(e.kind(), e.to_string())
+    }
+}
+
+impl From<ParseFailure> for ApiError {
+    fn from(failure: ParseFailure) -> Self {
+        Self::new(PARSE_ERROR, failure.message)
+    }
+}
+
+/// The outcome of a single submitted record.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Submitted {
+    /// `accepted`, or `replayed` for an exact replay of a row that was already applied.
+    pub status: String,
+}
+
+/// The outcome of a batch: how many records were applied and which were rejected.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct BatchOutcome {
+    pub accepted: usize,
+    pub rejected: Vec<BatchRejection>,
+}
+
+/// A record of a batch that was rejected, identified by its position in the batch.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct BatchRejection {
+    pub index: usize,
+    #[serde(flatten)]
+    pub error: ApiError,
+}
+
+/// A JSON-over-HTTP service in front of a shared `PaymentEngine`.
+///
+/// Records use the same fields as JSON Lines input. The endpoints are:
+/// - `GET /health`
+/// - `POST /transactions` with one record
+/// - `POST /transactions/batch` with an array of records, applied in order
+/// - `GET /accounts` and `GET /accounts/{client}`, with the rows of the output file
+///
+/// A rejected record gets a `422` response whose `ApiError` body names the `EngineError`, or a
+/// `500` if it broke an invariant, since the engine is then at fault rather than the record. In
+/// both cases the record was not applied, so the server keeps serving. A batch answers `200`
+/// with its rejections listed, unless one of its records broke an invariant: the batch then
+/// stops at that record, which is listed last, and answers `500`.
+/// Requests are handled on a pool of threads; the engine is behind a mutex, so records are
+/// still applied one at a time.
+pub struct Server {
+    http: Arc<tiny_http::Server>,
+    engine: Arc<Mutex<PaymentEngine>>,
+    stopping: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Binds to `addr` and starts serving on `threads` threads.
+    pub fn start(addr: impl ToSocketAddrs, engine: PaymentEngine, threads: usize) -> io::Result<Self> {
+        let http = Arc::new(tiny_http::Server::http(addr).map_err(io::Error::other)?);
+        let engine = Arc::new(Mutex::new(engine));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let (http, engine, stopping) = (Arc::clone(&http), Arc::clone(&engine), Arc::clone(&stopping));
+                thread::spawn(move || loop {
+                    match http.recv() {
+                        Ok(request) => handle(request, &engine),
+                        // `recv` also fails when `shutdown` unblocks it.
+                        Err(_) if stopping.load(Ordering::SeqCst) => break,
+                        Err(_) => continue,
+                    }
+                })
+            })
+            .collect();
+        Ok(Self { http, engine, stopping, workers })
+    }
+
+    /// The address the server is listening on, e.g. to find the port chosen for port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Blocks until every worker has stopped, then returns the engine.
+    pub fn wait(self) -> PaymentEngine {
+        for worker in self.workers {
+            worker.join().expect("server thread panicked");
+        }
+        // The workers held the only other references to the engine.
+        Arc::into_inner(self.engine)
+            .expect("engine still shared")
+            .into_inner()
+            .expect("engine lock poisoned")
+    }
+
+    /// Stops serving once the requests in progress are answered, then returns the engine.
+    pub fn shutdown(self) -> PaymentEngine {
+        self.stopping.store(true, Ordering::SeqCst);
+        for _ in &self.workers {
+            self.http.unblock();
+        }
+        self.wait()
+    }
+}
+
+fn handle(mut request: Request, engine: &Mutex<PaymentEngine>) {
+    let path = request.url().split('?').next().unwrap_or_default().to_string();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+    let (status, body) = match (&method, segments.as_slice()) {
+        (Method::Get, ["health"]) => (200, to_json(serde_json::json!({ "status": "ok" }))),
+        (Method::Post, ["transactions"]) => match read_json(&mut request) {
+            Ok(value) => submit(value, &mut lock(engine)),
+            Err(e) => error(400, e),
+        },
+        (Method::Post, ["transactions", "batch"]) => match read_json(&mut request) {
+            Ok(Value::Array(values)) => submit_batch(values, &mut lock(engine)),
+            Ok(_) => error(400, ApiError::new(PARSE_ERROR, "A batch must be a JSON array of records")),
+            Err(e) => error(400, e),
+        },
+        (Method::Get, ["accounts"]) => (200, to_json(lock(engine).output_records(OutputOrder::ClientId))),
+        (Method::Get, ["accounts", client]) => match client.parse::<u16>() {
+            Ok(client_id) => account(client_id, &lock(engine)),
+            Err(_) => error(404, ApiError::new("NotFound", format!("No such client: {client}"))),
+        },
+        (_, ["health"] | ["transactions"] | ["transactions", "batch"] | ["accounts"] | ["accounts", _]) => {
+            error(405, ApiError::new("MethodNotAllowed", format!("{method} is not allowed on {path}")))
+        }
+        _ => error(404, ApiError::new("NotFound", format!("No such endpoint: {path}"))),
+    };
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).expect("valid header");
+    let response = Response::from_data(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    // The client may have gone away; there is nobody left to tell.
+    let _ = request.respond(response);
+}
+
+fn lock(engine: &Mutex<PaymentEngine>) -> MutexGuard<'_, PaymentEngine> {
+    engine.lock().expect("engine lock poisoned")
+}
+
+fn read_json(request: &mut Request) -> Result<Value, ApiError> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| ApiError::new(PARSE_ERROR, e.to_string()))?;
+    serde_json::from_str(&body).map_err(|e| ApiError::new(PARSE_ERROR, e.to_string()))
+}
+
+fn submit(value: Value, engine: &mut PaymentEngine) -> (u16, String) {
+    let record = match parse_json_value(value) {
+        Ok(record) => record,
+        Err(failure) => return error(400, failure.into()),
+    };
+    let replays = engine.replays();
+    match engine.process(record) {
+        Ok(()) => {
+            let status = if engine.replays() > replays { "replayed" } else { "accepted" };
+            (200, to_json(Submitted { status: status.to_string() }))
+        }
+        Err(e @ EngineError::InvariantViolated(_)) => error(500, (&e).into()),
+        Err(e) => error(422, (&e).into()),
+    }
+}
+
+fn submit_batch(values: Vec<Value>, engine: &mut PaymentEngine) -> (u16, String) {
+    let mut outcome = BatchOutcome::default();
+    for (index, value) in values.into_iter().enumerate() {
+        let (error, broken) = match parse_json_value(value).map(|record| engine.process(record)) {
+            Ok(Ok(())) => {
+                outcome.accepted += 1;
+                continue;
+            }
+            Ok(Err(e)) => (ApiError::from(&e), matches!(e, EngineError::InvariantViolated(_))),
+            Err(failure) => (failure.into(), false),
+        };
+        outcome.rejected.push(BatchRejection { index, error });
+        if broken {
+            // The engine is at fault, as for a single record, so the rest of the batch is not applied.
+            return (500, to_json(outcome));
+        }
+    }
+    (200, to_json(outcome))
+}
+
+fn account(client_id: u16, engine: &PaymentEngine) -> (u16, String) {
+    let rows: Vec<OutputRecord> = engine
+        .output_records(OutputOrder::ClientId)
+        .into_iter()
+        .filter(|row| row.client_id == client_id)
+        .collect();
+    if rows.is_empty() {
+        error(404, (&EngineError::AccountNotFound(client_id)).into())
+    } else {
+        (200, to_json(rows))
+    }
+}
+
+fn error(status: u16, error: ApiError) -> (u16, String) {
+    (status, to_json(error))
+}
+
+fn to_json(body: impl Serialize) -> String {
+    serde_json::to_string(&body).expect("response bodies always serialize")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::models::InputRecord;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn start() -> (Server, SocketAddr) {
+        start_with(PaymentEngine::new())
+    }
+
+    fn start_with(engine: PaymentEngine) -> (Server, SocketAddr) {
+        let server = Server::start("127.0.0.1:0", engine, 2).unwrap();
+        let addr = server.local_addr().unwrap();
+        (server, addr)
+    }
+
+    /// Sends one request and returns the status code and the parsed JSON body.
+    fn call(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_submit_and_query_accounts() {
+        let (server, addr) = start();
+        assert_eq!(call(addr, "GET", "/health", ""), (200, serde_json::json!({ "status": "ok" })));
+
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 10.5}"#;
+        let (status, body) = call(addr, "POST", "/transactions", deposit);
+        assert_eq!((status, body["status"].as_str()), (200, Some("accepted")));
+        let (_, body) = call(addr, "POST", "/transactions", deposit);
+        assert_eq!(body["status"], "replayed");
+
+        let (status, body) = call(addr, "GET", "/accounts/1", "");
+        assert_eq!(status, 200);
+        assert_eq!(body[0]["available"], "10.5000");
+        let (status, body) = call(addr, "GET", "/accounts/2", "");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"], "AccountNotFound");
+
+        let engine = server.shutdown();
+        assert_eq!(engine.account(1).unwrap().total().to_string(), "10.5");
+    }
+
+    #[test]
+    fn test_rejections_return_structured_errors() {
+        let (server, addr) = start();
+        call(addr, "POST", "/transactions", r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "5"}"#);
+        let (status, body) =
+            call(addr, "POST", "/transactions", r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": "6"}"#);
+        assert_eq!(status, 422);
+        let error: ApiError = serde_json::from_value(body).unwrap();
+        assert_eq!(error, ApiError::from(&EngineError::InsufficientFunds(1, "6".parse().unwrap())));
+
+        let (status, body) = call(addr, "POST", "/transactions", r#"{"type": "deposit", "client": 1}"#);
+        assert_eq!((status, body["error"].as_str()), (400, Some(PARSE_ERROR)));
+        let (status, _) = call(addr, "GET", "/transactions", "");
+        assert_eq!(status, 405);
+        let (status, _) = call(addr, "GET", "/nothing", "");
+        assert_eq!(status, 404);
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_batch_applies_records_in_order() {
+        let (server, addr) = start();
+        let batch = r#"[
+            {"type": "deposit", "client": 1, "tx": 1, "amount": "5"},
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": "7"},
+            {"type": "deposit", "client": 2, "tx": 3, "amount": "2"},
+            {"type": "dispute", "client": 2},
+            {"type": "dispute", "client": 1, "tx": 1}
+        ]"#;
+        let (status, body) = call(addr, "POST", "/transactions/batch", batch);
+        assert_eq!(status, 200);
+        let outcome: BatchOutcome = serde_json::from_value(body).unwrap();
+        assert_eq!(outcome.accepted, 3);
+        let rejected: Vec<(usize, &str)> = outcome.rejected.iter().map(|r| (r.index, r.error.error.as_str())).collect();
+        assert_eq!(rejected, [(1, "InsufficientFunds"), (3, PARSE_ERROR)]);
+
+        let (_, body) = call(addr, "GET", "/accounts", "");
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[0]["held"], "5.0000");
+        server.shutdown();
+    }
+
+    #[test]
+    fn test_batch_stops_at_an_invariant_violation() {
+        let mut engine = PaymentEngine::new();
+        engine.process(InputRecord::deposit(1, 1, "10".parse().unwrap())).unwrap();
+        let mut snapshot = engine.snapshot();
+        snapshot.accounts[0].held = "-1".parse().unwrap();
+        let mut engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        engine.set_invariant_checks(true);
+        let (server, addr) = start_with(engine);
+        let batch = r#"[
+            {"type": "deposit", "client": 2, "tx": 2, "amount": "5"},
+            {"type": "deposit", "client": 1, "tx": 3, "amount": "1"},
+            {"type": "deposit", "client": 2, "tx": 4, "amount": "5"}
+        ]"#;
+        let (status, body) = call(addr, "POST", "/transactions/batch", batch);
+        assert_eq!(status, 500);
+        let outcome: BatchOutcome = serde_json::from_value(body).unwrap();
+        assert_eq!(outcome.accepted, 1);
+        let rejected: Vec<(usize, &str)> = outcome.rejected.iter().map(|r| (r.index, r.error.error.as_str())).collect();
+        assert_eq!(rejected, [(1, "InvariantViolated")]);
+        let engine = server.shutdown();
+        assert_eq!(engine.account(2).unwrap().total().to_string(), "5");
+    }
+}
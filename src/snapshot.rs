@@ -0,0 +1,249 @@
+use crate::error::SnapshotError;
+use crate::models::{AccountStatus, AdminAction, Currency, TransactionDirection, TransactionStatus};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// The snapshot format version written by this build. Every earlier version is still read:
+/// - 1: the first format, read in the unspecified currency
+/// - 2: per-currency balances
+/// - 3: transfers
+/// - 4: partial disputes
+/// - 5: admin actions
+/// - 6: fees
+/// - 7: content hashes of ledger entries
+pub const SNAPSHOT_VERSION: u32 = 7;
+
+/// The complete state of a `PaymentEngine`, suitable for carrying over to the next run.
+/// Entries are sorted by ID so the same state always serializes to identical bytes.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Snapshot {
+    pub version: u32,
+    /// The last write-ahead log entry reflected in this snapshot (0 when no log is used).
+    #[serde(default)]
+    pub wal_lsn: u64,
+    pub accounts: Vec<AccountSnapshot>,
+    pub transactions: Vec<TransactionSnapshot>,
+    /// Every admin action applied so far, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub admin_actions: Vec<AdminAction>,
+    /// Fees collected so far, per currency.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fee_revenue: Vec<FeeRevenueSnapshot>,
+}
+
+/// The fees collected in one currency.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct FeeRevenueSnapshot {
+    #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+    pub currency: Currency,
+    pub amount: Decimal,
+}
+
+/// The persisted state of one client account.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct AccountSnapshot {
+    pub client: u16,
+    #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+    #[serde(default, skip_serializing_if = "AccountStatus::is_active")]
+    pub status: AccountStatus,
+}
+
+/// The persisted state of one ledger entry, including its dispute status.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct TransactionSnapshot {
+    pub tx: u32,
+    pub client: u16,
+    pub amount: Decimal,
+    #[serde(default, skip_serializing_if = "Currency::is_unspecified")]
+    pub currency: Currency,
+    pub direction: TransactionDirection,
+    pub status: TransactionStatus,
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub disputed: Decimal,
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub resolved: Decimal,
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub charged_back: Decimal,
+    /// For a transfer leg, the client on the other side. Each leg is a separate entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// The content hash of the row that created the entry, used to recognize replays.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<u64>,
+}
+
+impl Snapshot {
+    /// Serializes the snapshot as JSON.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), SnapshotError> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Reads a snapshot, rejecting versions this build does not understand.
+    pub fn read_from<R: Read>(reader: R) -> Result<Self, SnapshotError> {
+        // Check the version before the layout, which may differ between versions.
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(0);
+        if !(1..=u64::from(SNAPSHOT_VERSION)).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// Writes the snapshot to `path` atomically: a crash never leaves a half-written file behind,
+    /// and once this returns the new snapshot survives a crash as well.
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        self.write_to(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)?;
+        // The rename is only durable once the directory entry itself reaches the disk.
+        #[cfg(unix)]
+        {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Loads a snapshot previously written by `save`.
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        Self::read_from(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::PaymentEngine;
+    use crate::error::EngineError;
+    use crate::config::EngineConfig;
+    use crate::fees::Fee;
+    use crate::models::{InputRecord, TransactionType};
+    use rust_decimal_macros::dec;
+
+    fn engine_with_history() -> PaymentEngine {
+        let mut engine = PaymentEngine::new();
+        engine.process(InputRecord::deposit(2, 1, dec!(100.0))).unwrap();
+        engine.process(InputRecord::withdrawal(2, 2, dec!(25.5))).unwrap();
+        engine.process(InputRecord::deposit(1, 3, dec!(10.0))).unwrap();
+        engine.process(InputRecord::dispute(1, 3)).unwrap();
+        engine.process(InputRecord::chargeback(1, 3)).unwrap();
+        engine.process(InputRecord::dispute(2, 1)).unwrap();
+        engine.process(InputRecord::deposit(4, 4, dec!(3.0))).unwrap();
+        engine.process(InputRecord::transfer(4, 5, 5, dec!(1.5))).unwrap();
+        engine.process(InputRecord::dispute(5, 5)).unwrap();
+        engine.process(InputRecord::admin(TransactionType::Freeze, 4, 7, "kyc")).unwrap();
+        engine
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let engine = engine_with_history();
+        let mut bytes = Vec::new();
+        engine.snapshot().write_to(&mut bytes).unwrap();
+        let restored = PaymentEngine::from_snapshot(Snapshot::read_from(bytes.as_slice()).unwrap(), EngineConfig::default());
+        assert_eq!(restored.snapshot(), engine.snapshot());
+        assert_eq!(restored.account(2).unwrap().held(), dec!(100.0));
+        assert!(restored.account(1).unwrap().locked());
+        assert_eq!(restored.transaction(3).unwrap().status, TransactionStatus::ChargedBack);
+        assert_eq!(restored.transfer_credit(5).unwrap().status, TransactionStatus::Disputed);
+        assert_eq!(restored.account(4).unwrap().status(), AccountStatus::Frozen);
+        assert_eq!(restored.admin_actions(), engine.admin_actions());
+    }
+
+    #[test]
+    fn test_dispute_carried_over_to_next_run() {
+        let snapshot = engine_with_history().snapshot();
+        let mut engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        engine.process(InputRecord::resolve(2, 1)).unwrap();
+        engine.process(InputRecord::dispute(2, 2)).unwrap();
+        let account = engine.account(2).unwrap();
+        assert_eq!(account.available(), dec!(74.5));
+        assert_eq!(account.held(), dec!(25.5));
+    }
+
+    #[test]
+    fn test_currencies_survive_round_trip() {
+        let config = EngineConfig { currencies: true, ..EngineConfig::default() };
+        let mut engine = PaymentEngine::from_snapshot(engine_with_history().snapshot(), config.clone());
+        let eur = "EUR".parse().unwrap();
+        engine.process(InputRecord::deposit(2, 6, dec!(7.0)).in_currency(eur)).unwrap();
+        let mut bytes = Vec::new();
+        engine.snapshot().write_to(&mut bytes).unwrap();
+        let restored = PaymentEngine::from_snapshot(Snapshot::read_from(bytes.as_slice()).unwrap(), config);
+        assert_eq!(restored.account_in(2, eur).unwrap().available(), dec!(7.0));
+        assert_eq!(restored.transaction(6).unwrap().currency, eur);
+    }
+
+    #[test]
+    fn test_version_1_snapshot_is_single_currency() {
+        let json = r#"{"version":1,"accounts":[{"client":1,"available":"5","held":"0","locked":false}],
+                       "transactions":[{"tx":1,"client":1,"amount":"5","direction":"credit","status":"normal"}]}"#;
+        let engine = PaymentEngine::from_snapshot(Snapshot::read_from(json.as_bytes()).unwrap(), EngineConfig::default());
+        assert_eq!(engine.account(1).unwrap().available(), dec!(5));
+        assert!(engine.transaction(1).unwrap().currency.is_unspecified());
+    }
+
+    #[test]
+    fn test_pre_partial_dispute_snapshot_disputes_full_amount() {
+        let json = r#"{"version":3,"accounts":[{"client":1,"available":"0","held":"5","locked":false}],
+                       "transactions":[{"tx":1,"client":1,"amount":"5","direction":"credit","status":"disputed"}]}"#;
+        let mut engine = PaymentEngine::from_snapshot(Snapshot::read_from(json.as_bytes()).unwrap(), EngineConfig::default());
+        assert_eq!(engine.transaction(1).unwrap().disputed, dec!(5));
+        engine.process(InputRecord::resolve(1, 1)).unwrap();
+        assert_eq!(engine.account(1).unwrap().available(), dec!(5));
+    }
+
+    #[test]
+    fn test_unsupported_version_is_rejected() {
+        let result = Snapshot::read_from(r#"{"version":99,"accounts":[],"transactions":[]}"#.as_bytes());
+        assert!(matches!(result, Err(SnapshotError::UnsupportedVersion(99))));
+    }
+
+    #[test]
+    fn test_fee_revenue_survives_round_trip() {
+        let mut config = EngineConfig::default();
+        config.fees.set_default(TransactionType::Withdrawal, Fee { percent: dec!(1), flat: dec!(0) });
+        let mut engine = PaymentEngine::with_config(config.clone());
+        engine.process(InputRecord::deposit(1, 1, dec!(100.0))).unwrap();
+        engine.process(InputRecord::withdrawal(1, 2, dec!(50.0))).unwrap();
+        let mut bytes = Vec::new();
+        engine.snapshot().write_to(&mut bytes).unwrap();
+        let restored = PaymentEngine::from_snapshot(Snapshot::read_from(bytes.as_slice()).unwrap(), config);
+        assert_eq!(restored.fee_revenue(), engine.fee_revenue());
+        assert_eq!(restored.account(1).unwrap().available(), dec!(49.5));
+    }
+
+    #[test]
+    fn test_resent_file_is_not_applied_twice() {
+        let snapshot = engine_with_history().snapshot();
+        let mut engine = PaymentEngine::from_snapshot(snapshot, EngineConfig::default());
+        engine.process(InputRecord::deposit(2, 1, dec!(100.0))).unwrap();
+        engine.process(InputRecord::withdrawal(2, 2, dec!(25.5))).unwrap();
+        assert_eq!(engine.replays(), 2);
+        assert_eq!(engine.account(2).unwrap().total(), dec!(74.5));
+        let result = engine.process(InputRecord::deposit(2, 1, dec!(99.0)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+    }
+
+    #[test]
+    fn test_reuse_of_id_without_content_hash_is_rejected() {
+        let json = r#"{"version":6,"accounts":[{"client":1,"available":"5","held":"0","locked":false}],
+                       "transactions":[{"tx":1,"client":1,"amount":"5","direction":"credit","status":"normal"}]}"#;
+        let mut engine = PaymentEngine::from_snapshot(Snapshot::read_from(json.as_bytes()).unwrap(), EngineConfig::default());
+        let result = engine.process(InputRecord::deposit(1, 1, dec!(5)));
+        assert_eq!(result, Err(EngineError::DuplicateTransactionId(1)));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::engine::PaymentEngine;
+use crate::error::EngineError;
+use crate::events::{Event, MemorySink};
+use crate::format::DataFormat;
+use crate::models::{
+    serialize_option_with_four_decimals, serialize_with_four_decimals, Currency, InputRecord, TransactionStatus,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// What a statement line did to the account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Activity {
+    Deposit,
+    Withdrawal,
+    /// The sending leg of a transfer.
+    TransferOut,
+    /// The receiving leg of a transfer.
+    TransferIn,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Unlock,
+    Freeze,
+    Close,
+}
+
+/// One line of a client's statement: an accepted row and the balances right after it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatementLine {
+    pub client: u16,
+    /// Only written with currencies enabled, as in the account output.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub activity: Activity,
+    /// The other client of a transfer.
+    pub counterparty: Option<u16>,
+    /// The amount the row moved or, for a dispute, resolve or chargeback, the portion it acted on.
+    /// Admin rows have none.
+    #[serde(serialize_with = "serialize_option_with_four_decimals")]
+    pub amount: Option<Decimal>,
+    #[serde(serialize_with = "serialize_with_four_decimals")]
+    pub fee: Decimal,
+    #[serde(serialize_with = "serialize_with_four_decimals")]
+    pub available: Decimal,
+    #[serde(serialize_with = "serialize_with_four_decimals")]
+    pub held: Decimal,
+    #[serde(serialize_with = "serialize_with_four_decimals")]
+    pub total: Decimal,
+    pub locked: bool,
+    /// Where the transaction's disputes stood at the end of the run; empty if it was never
+    /// disputed.
+    pub outcome: Option<TransactionStatus>,
+}
+
+/// Builds account statements while records are applied to an engine.
+///
+/// Every line is derived from the engine itself: the amounts come from its domain events and the
+/// balances are read from the account after each accepted row, so a statement always agrees
+/// with the account output. Rejected rows changed nothing and get no line. Lines are kept in
+/// memory until `finish`, but only for the clients a statement is generated for.
+pub struct StatementGenerator {
+    engine: PaymentEngine,
+    events: MemorySink,
+    /// The client to generate a statement for, or `None` for every client.
+    client: Option<u16>,
+    lines: Vec<StatementLine>,
+}
+
+impl StatementGenerator {
+    /// Generates statements for `client`, or for every client, from the records applied to
+    /// `engine` from now on. Takes over the engine's event sink.
+    pub fn new(mut engine: PaymentEngine, client: Option<u16>) -> Self {
+        let events = MemorySink::default();
+        engine.set_event_sink(Box::new(events.clone()));
+        Self {
+            engine,
+            events,
+            client,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Applies a record to the engine and adds its lines to the statements.
+    pub fn process(&mut self, record: InputRecord) -> Result<(), EngineError> {
+        let result = self.engine.process(record);
+        let events = self.events.take_events();
+        if result.is_ok() {
+            self.add_lines(events);
+        }
+        result
+    }
+
+    /// Fills in the dispute outcomes and returns the statement lines, grouped by client and in
+    /// the order they were applied, together with the engine.
+    pub fn finish(mut self) -> (Vec<StatementLine>, PaymentEngine) {
+        self.engine.take_event_sink();
+        let multi_currency = self.engine.config().currencies;
+        for line in &mut self.lines {
+            let ledger = match line.activity {
+                Activity::TransferIn => self.engine.transfer_credit(line.tx),
+                Activity::Unlock | Activity::Freeze | Activity::Close => None,
+                _ => self.engine.transaction(line.tx).or_else(|| self.engine.transfer_credit(line.tx)),
+            };
+            line.outcome = ledger
+                .filter(|tx| tx.client_id == line.client)
+                .map(|tx| tx.status)
+                .filter(|status| *status != TransactionStatus::Normal);
+            if !multi_currency {
+                line.currency = None;
+            }
+        }
+        self.lines.sort_by_key(|line| line.client);
+        (self.lines, self.engine)
+    }
+
+    /// Turns the events of one accepted row into at most one line per client and currency.
+    fn add_lines(&mut self, events: Vec<Event>) {
+        let mut lines: Vec<StatementLine> = Vec::new();
+        let mut line = |client: u16,
+                        currency: Currency,
+                        tx: u32,
+                        activity: Activity,
+                        counterparty: Option<u16>,
+                        amount: Option<Decimal>| {
+            lines.push(StatementLine {
+                client,
+                currency: Some(currency),
+                tx,
+                activity,
+                counterparty,
+                amount,
+                fee: Decimal::ZERO,
+                available: Decimal::ZERO,
+                held: Decimal::ZERO,
+                total: Decimal::ZERO,
+                locked: false,
+                outcome: None,
+            })
+        };
+        let mut fees = Vec::new();
+        for event in events {
+            match event {
+                Event::FundsDeposited { tx, client, currency, amount } => {
+                    line(client, currency, tx, Activity::Deposit, None, Some(amount))
+                }
+                Event::FundsWithdrawn { tx, client, currency, amount } => {
+                    line(client, currency, tx, Activity::Withdrawal, None, Some(amount))
+                }
+                Event::FundsTransferred { tx, client, counterparty, currency, amount } => {
+                    line(client, currency, tx, Activity::TransferOut, Some(counterparty), Some(amount));
+                    line(counterparty, currency, tx, Activity::TransferIn, Some(client), Some(amount));
+                }
+                Event::FundsHeld { tx, client, currency, amount } => {
+                    line(client, currency, tx, Activity::Dispute, None, Some(amount))
+                }
+                Event::FundsReleased { tx, client, currency, amount } => {
+                    line(client, currency, tx, Activity::Resolve, None, Some(amount))
+                }
+                Event::ChargebackApplied { tx, client, currency, amount } => {
+                    line(client, currency, tx, Activity::Chargeback, None, Some(amount))
+                }
+                // Reason codes are internal, so they stay off client statements.
+                Event::AccountUnlocked { tx, client, currency, .. } => {
+                    line(client, currency, tx, Activity::Unlock, None, None)
+                }
+                Event::AccountFrozen { tx, client, currency, .. } => {
+                    line(client, currency, tx, Activity::Freeze, None, None)
+                }
+                Event::AccountClosed { tx, client, currency, .. } => {
+                    line(client, currency, tx, Activity::Close, None, None)
+                }
+                Event::FeeCharged { client, currency, amount, .. } => fees.push((client, currency, amount)),
+                // The locked column shows it.
+                Event::AccountLocked { .. } | Event::TransactionRejected { .. } => {}
+            }
+        }
+        for (client, currency, fee) in fees {
+            if let Some(line) = lines.iter_mut().find(|l| l.client == client && l.currency == Some(currency)) {
+                line.fee += fee;
+            }
+        }
+        for mut line in lines {
+            if self.client.is_some_and(|client| client != line.client) {
+                continue;
+            }
+            if let Some(account) = self.engine.account_in(line.client, line.currency.unwrap_or_default()) {
+                line.available = account.available();
+                line.held = account.held();
+                line.total = account.total();
+                line.locked = account.locked();
+            }
+            self.lines.push(line);
+        }
+    }
+}
+
+/// Writes statement lines as CSV or JSON Lines.
+pub fn write_statement<W: Write>(lines: &[StatementLine], writer: W, format: DataFormat) -> io::Result<()> {
+    match format {
+        DataFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(writer);
+            for line in lines {
+                wtr.serialize(line)?;
+            }
+            wtr.flush()
+        }
+        DataFormat::JsonLines => {
+            let mut writer = writer;
+            for line in lines {
+                serde_json::to_writer(&mut writer, line)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EngineConfig;
+    use crate::fees::{Fee, FeeSchedule};
+    use crate::models::TransactionType;
+    use rust_decimal_macros::dec;
+
+    fn statement(client: Option<u16>, records: Vec<InputRecord>) -> Vec<StatementLine> {
+        let mut fees = FeeSchedule::default();
+        fees.set_default(TransactionType::Withdrawal, Fee { percent: dec!(0), flat: dec!(0.5) });
+        let engine = PaymentEngine::with_config(EngineConfig { fees, ..EngineConfig::default() });
+        let mut generator = StatementGenerator::new(engine, client);
+        for record in records {
+            let _ = generator.process(record);
+        }
+        generator.finish().0
+    }
+
+    fn records() -> Vec<InputRecord> {
+        vec![
+            InputRecord::deposit(1, 1, dec!(10.0)),
+            InputRecord::deposit(2, 2, dec!(5.0)),
+            InputRecord::withdrawal(1, 3, dec!(2.0)),
+            InputRecord::withdrawal(1, 4, dec!(100.0)),
+            InputRecord::transfer(1, 5, 2, dec!(3.0)),
+            InputRecord::dispute(1, 1),
+            InputRecord::chargeback(1, 1),
+        ]
+    }
+
+    #[test]
+    fn test_statement_of_one_client() {
+        let mut wtr = Vec::new();
+        write_statement(&statement(Some(1), records()), &mut wtr, DataFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(wtr).unwrap(),
+            "client,tx,type,counterparty,amount,fee,available,held,total,locked,outcome\n\
+             1,1,deposit,,10.0000,0.0000,10.0000,0.0000,10.0000,false,charged_back\n\
+             1,3,withdrawal,,2.0000,0.5000,7.5000,0.0000,7.5000,false,\n\
+             1,5,transfer_out,2,3.0000,0.0000,4.5000,0.0000,4.5000,false,\n\
+             1,1,dispute,,10.0000,0.0000,-5.5000,10.0000,4.5000,false,charged_back\n\
+             1,1,chargeback,,10.0000,0.0000,-5.5000,0.0000,-5.5000,true,charged_back\n"
+        );
+    }
+
+    #[test]
+    fn test_statements_of_all_clients_are_grouped_by_client() {
+        let lines = statement(None, records());
+        let clients: Vec<(u16, Activity)> = lines.iter().map(|line| (line.client, line.activity)).collect();
+        assert_eq!(clients.len(), 7);
+        assert_eq!(&clients[5..], [(2, Activity::Deposit), (2, Activity::TransferIn)]);
+        assert_eq!(lines[6].total, dec!(8.0));
+        assert_eq!(lines[6].counterparty, Some(1));
+    }
+}
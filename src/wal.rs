@@ -0,0 +1,340 @@
+use crate::config::EngineConfig;
+use crate::engine::PaymentEngine;
+use crate::error::WalError;
+use crate::events::EventSink;
+use crate::models::InputRecord;
+use crate::snapshot::Snapshot;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Size of the per-entry header: payload length followed by its CRC-32, both little-endian `u32`s.
+const HEADER_LEN: u64 = 8;
+
+/// One accepted record, as stored in the log.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct WalEntry {
+    /// Log sequence number, strictly increasing across checkpoints.
+    pub lsn: u64,
+    /// The input row the record came from.
+    pub row: u64,
+    pub record: InputRecord,
+}
+
+/// An append-only, checksummed log of accepted records.
+///
+/// Each entry is a JSON payload framed by its length and CRC-32. Entries are flushed to disk
+/// before `append` returns, so after a crash the log holds every record the engine accepted,
+/// plus at most one torn entry at the end, which `open` detects and truncates.
+pub struct WriteAheadLog {
+    file: File,
+    next_lsn: u64,
+}
+
+impl WriteAheadLog {
+    /// Opens or creates the log at `path` and returns its valid entries.
+    /// A torn trailing entry is truncated; its size in bytes is returned alongside the entries.
+    pub fn open(path: &Path) -> Result<(Self, Vec<WalEntry>, u64), WalError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&mut file);
+        let mut entries: Vec<WalEntry> = Vec::new();
+        let mut offset = 0;
+
+        while offset < file_len {
+            let mut header = [0u8; HEADER_LEN as usize];
+            if offset + HEADER_LEN > file_len {
+                break;
+            }
+            reader.read_exact(&mut header)?;
+            let len = u64::from(u32::from_le_bytes(header[..4].try_into().unwrap()));
+            let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+            let end = offset + HEADER_LEN + len;
+            if end > file_len {
+                break;
+            }
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+            let entry = (crc32fast::hash(&payload) == checksum)
+                .then(|| serde_json::from_slice::<WalEntry>(&payload).ok())
+                .flatten();
+            match entry {
+                Some(entry) => entries.push(entry),
+                // Only the last entry can be torn by a crash; damage elsewhere is corruption.
+                None if end == file_len => break,
+                None => return Err(WalError::Corrupt(offset)),
+            }
+            offset = end;
+        }
+
+        let torn = file_len - offset;
+        if torn > 0 {
+            file.set_len(offset)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        let next_lsn = entries.last().map_or(1, |e| e.lsn + 1);
+        Ok((Self { file, next_lsn }, entries, torn))
+    }
+
+    /// Durably appends an accepted record and returns its log sequence number.
+    pub fn append(&mut self, row: u64, record: &InputRecord) -> Result<u64, WalError> {
+        let entry = WalEntry {
+            lsn: self.next_lsn,
+            row,
+            record: record.clone(),
+        };
+        let payload = serde_json::to_vec(&entry)?;
+        let len = u32::try_from(payload.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "WAL entry too large"))?;
+        let mut frame = Vec::with_capacity(HEADER_LEN as usize + payload.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()?;
+        self.next_lsn += 1;
+        Ok(entry.lsn)
+    }
+
+    /// Discards every entry, once a checkpoint snapshot covers them.
+    pub fn reset(&mut self) -> Result<(), WalError> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// The sequence number of the most recently appended entry (0 if none yet).
+    pub fn last_lsn(&self) -> u64 {
+        self.next_lsn - 1
+    }
+}
+
+/// What `WalEngine::recover` found in the log.
+#[derive(Debug, Default, PartialEq)]
+pub struct Recovery {
+    /// Entries newer than the snapshot that were replayed.
+    pub replayed: usize,
+    /// The input row of the last replayed entry, if any.
+    pub last_row: Option<u64>,
+    /// Bytes of a torn trailing entry that were truncated.
+    pub truncated_bytes: u64,
+}
+
+/// A `PaymentEngine` whose accepted records are written ahead to a log.
+pub struct WalEngine {
+    engine: PaymentEngine,
+    wal: WriteAheadLog,
+    /// The input row of a record that was applied but could not be logged. The engine is then
+    /// ahead of the log, so no further record is accepted until a checkpoint covers it.
+    unlogged: Option<u64>,
+}
+
+impl WalEngine {
+    /// Rebuilds the exact engine state from the last snapshot (if any) plus every newer log entry,
+    /// then continues logging to the same file.
+    pub fn recover(
+        snapshot: Option<Snapshot>,
+        config: EngineConfig,
+        wal_path: &Path,
+    ) -> Result<(Self, Recovery), WalError> {
+        let (mut wal, entries, truncated_bytes) = WriteAheadLog::open(wal_path)?;
+        let snapshot_lsn = snapshot.as_ref().map_or(0, |s| s.wal_lsn);
+        let mut engine = match snapshot {
+            Some(snapshot) => PaymentEngine::from_snapshot(snapshot, config),
+            None => PaymentEngine::with_config(config),
+        };
+
+        let mut recovery = Recovery {
+            truncated_bytes,
+            ..Recovery::default()
+        };
+        for entry in entries.into_iter().filter(|e| e.lsn > snapshot_lsn) {
+            // Every entry was accepted when it was logged; a rejection means the log does not
+            // belong to this snapshot.
+            engine
+                .process(entry.record)
+                .map_err(|e| WalError::Replay(entry.lsn, e))?;
+            recovery.replayed += 1;
+            recovery.last_row = Some(entry.row);
+        }
+        wal.next_lsn = wal.next_lsn.max(snapshot_lsn + 1);
+        Ok((Self { engine, wal, unlogged: None }, recovery))
+    }
+
+    /// Processes a record and, if it was accepted, logs it before returning.
+    ///
+    /// If the record was applied but the log write fails, the state holds a record that recovery
+    /// would not replay. Every later record then fails with `Unlogged` until `checkpoint` saves
+    /// the state, record included.
+    pub fn process(&mut self, row: u64, record: InputRecord) -> Result<(), WalError> {
+        if let Some(row) = self.unlogged {
+            return Err(WalError::Unlogged(row));
+        }
+        self.engine.process(record.clone())?;
+        if let Err(e) = self.wal.append(row, &record) {
+            self.unlogged = Some(row);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Saves a snapshot covering every logged entry, and any record that could not be logged,
+    /// to `path`, then empties the log.
+    pub fn checkpoint(&mut self, path: &Path) -> Result<(), WalError> {
+        let mut snapshot = self.engine.snapshot();
+        snapshot.wal_lsn = self.wal.last_lsn();
+        snapshot.save(path)?;
+        self.wal.reset()?;
+        self.unlogged = None;
+        Ok(())
+    }
+
+    /// Sends domain events of the records processed from now on to `sink`. Entries replayed by
+    /// `recover` were emitted when they were first applied, so they emit none.
+    pub fn set_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.engine.set_event_sink(sink);
+    }
+
+    /// Checks invariants after each record processed from now on, as `PaymentEngine` does. A
+    /// record that breaks one is undone by the engine and not logged, so the log still matches
+    /// the state.
+    pub fn set_invariant_checks(&mut self, enabled: bool) {
+        self.engine.set_invariant_checks(enabled);
+    }
+
+    /// Flushes the event sink, reporting the first event that could not be written.
+    pub fn flush_events(&mut self) -> io::Result<()> {
+        self.engine.flush_events()
+    }
+
+    /// The engine holding the current state.
+    pub fn engine(&self) -> &PaymentEngine {
+        &self.engine
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::EngineError;
+    use rust_decimal_macros::dec;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("payment-engine-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn deposit(tx_id: u32, amount: rust_decimal::Decimal) -> InputRecord {
+        InputRecord::deposit(1, tx_id, amount)
+    }
+
+    #[test]
+    fn test_recovery_replays_accepted_records() {
+        let path = temp_path("replay.wal");
+        let (mut engine, _) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        engine.process(1, deposit(1, dec!(10.0))).unwrap();
+        let rejected = engine.process(2, deposit(1, dec!(5.0)));
+        assert!(matches!(rejected, Err(WalError::Engine(EngineError::DuplicateTransactionId(1)))));
+        engine.process(3, InputRecord::dispute(1, 1)).unwrap();
+        let expected = engine.engine().snapshot();
+        drop(engine);
+
+        let (recovered, recovery) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        assert_eq!(recovery, Recovery { replayed: 2, last_row: Some(3), truncated_bytes: 0 });
+        assert_eq!(recovered.engine().snapshot(), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_trailing_entry_is_truncated() {
+        let path = temp_path("torn.wal");
+        let (mut engine, _) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        engine.process(1, deposit(1, dec!(10.0))).unwrap();
+        engine.process(2, deposit(2, dec!(20.0))).unwrap();
+        drop(engine);
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(full_len - 3).unwrap();
+
+        let (mut recovered, recovery) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        assert_eq!(recovery.replayed, 1);
+        assert_eq!(recovery.last_row, Some(1));
+        assert!(recovery.truncated_bytes > 0);
+        assert_eq!(recovered.engine().account(1).unwrap().available(), dec!(10.0));
+
+        // The log stays usable after truncation.
+        recovered.process(2, deposit(2, dec!(20.0))).unwrap();
+        drop(recovered);
+        let (recovered, recovery) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        assert_eq!(recovery.replayed, 2);
+        assert_eq!(recovered.engine().account(1).unwrap().available(), dec!(30.0));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_entry_before_the_tail_is_an_error() {
+        let path = temp_path("corrupt.wal");
+        let (mut engine, _) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        engine.process(1, deposit(1, dec!(10.0))).unwrap();
+        engine.process(2, deposit(2, dec!(20.0))).unwrap();
+        drop(engine);
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN as usize + 2] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let result = WalEngine::recover(None, EngineConfig::default(), &path);
+        assert!(matches!(result, Err(WalError::Corrupt(0))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_records_are_refused_after_a_failed_log_write_until_a_checkpoint() {
+        let path = temp_path("unlogged.wal");
+        let state = temp_path("unlogged.json");
+        let (mut engine, _) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        engine.process(1, deposit(1, dec!(10.0))).unwrap();
+        // A read-only handle makes the next append fail after the engine applied the record.
+        engine.wal.file = File::open(&path).unwrap();
+        assert!(matches!(engine.process(2, deposit(2, dec!(20.0))), Err(WalError::Io(_))));
+        assert!(matches!(engine.process(3, deposit(3, dec!(5.0))), Err(WalError::Unlogged(2))));
+        assert_eq!(engine.engine().account(1).unwrap().available(), dec!(30.0));
+
+        engine.wal.file = OpenOptions::new().write(true).open(&path).unwrap();
+        engine.checkpoint(&state).unwrap();
+        engine.process(3, deposit(3, dec!(5.0))).unwrap();
+        drop(engine);
+        let snapshot = Snapshot::load(&state).unwrap();
+        let (recovered, _) = WalEngine::recover(Some(snapshot), EngineConfig::default(), &path).unwrap();
+        assert_eq!(recovered.engine().account(1).unwrap().available(), dec!(35.0));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&state).unwrap();
+    }
+
+    #[test]
+    fn test_checkpoint_skips_entries_covered_by_snapshot() {
+        let path = temp_path("checkpoint.wal");
+        let state = temp_path("checkpoint.json");
+        let (mut engine, _) = WalEngine::recover(None, EngineConfig::default(), &path).unwrap();
+        engine.process(1, deposit(1, dec!(10.0))).unwrap();
+        engine.checkpoint(&state).unwrap();
+        engine.process(2, deposit(2, dec!(20.0))).unwrap();
+        drop(engine);
+
+        let snapshot = Snapshot::load(&state).unwrap();
+        assert_eq!(snapshot.wal_lsn, 1);
+        let (recovered, recovery) = WalEngine::recover(Some(snapshot), EngineConfig::default(), &path).unwrap();
+        assert_eq!(recovery.replayed, 1);
+        assert_eq!(recovered.engine().account(1).unwrap().available(), dec!(30.0));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&state).unwrap();
+    }
+}